@@ -0,0 +1,356 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::path::Path;
+
+use actix_web::{http, web, HttpRequest, HttpResponse};
+use libnixstore::Radix;
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::hash::hash_part;
+use crate::{cache_control_no_store, nixhash};
+
+fn default_largest() -> usize {
+    10
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GcPlanParams {
+    /// Number of entries to return in `largest`.
+    #[serde(default = "default_largest")]
+    largest: usize,
+    /// Like `nix-collect-garbage --max-freed`, plan to stop once this many bytes are freed.
+    max_freed: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct DeadPath {
+    path: String,
+    nar_size: u64,
+    #[serde(skip)]
+    refs: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct GcPlan {
+    /// All paths a garbage collection would delete, sorted by store path.
+    paths: Vec<DeadPath>,
+    /// Sum of the nar sizes of `paths`. The space actually freed can be smaller because of
+    /// hard-linked files in optimised stores.
+    total_nar_size: u64,
+    largest: Vec<DeadPath>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PinRequest {
+    /// Store path or its hash part.
+    path: String,
+}
+
+#[derive(Debug, Serialize)]
+struct GcRoot {
+    name: String,
+    path: String,
+}
+
+// Compare in constant time so the token can't be guessed byte by byte.
+fn token_matches(expected: &str, given: &str) -> bool {
+    expected.len() == given.len()
+        && expected
+            .bytes()
+            .zip(given.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+/// Returns the response to send if `req` may not use the admin endpoints, `None` otherwise.
+/// Admin endpoints are disabled unless `admin_token_path` is configured.
+pub(crate) fn check_token(req: &HttpRequest, config: &Config) -> Option<HttpResponse> {
+    let Some(expected) = &config.admin_token else {
+        return Some(
+            HttpResponse::NotFound()
+                .insert_header(cache_control_no_store())
+                .finish(),
+        );
+    };
+    let given = req
+        .headers()
+        .get(http::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    match given {
+        Some(given) if token_matches(expected, given) => None,
+        _ => Some(
+            HttpResponse::Unauthorized()
+                .insert_header(cache_control_no_store())
+                .insert_header((http::header::WWW_AUTHENTICATE, "Bearer"))
+                .finish(),
+        ),
+    }
+}
+
+/// Returns whether `req` carries the admin token.
+pub(crate) fn authorized(req: &HttpRequest, config: &Config) -> bool {
+    check_token(req, config).is_none()
+}
+
+/// Picks the dead paths a garbage collection bounded by `max_freed` bytes would delete. Nix
+/// deletes dead paths in no particular order until it freed enough, so the plan takes the largest
+/// ones first. A path is only deleted together with the dead paths referring to it.
+fn bound_freed(paths: Vec<DeadPath>, max_freed: u64) -> Vec<DeadPath> {
+    let mut referrers = BTreeMap::<&str, Vec<&str>>::new();
+    for p in &paths {
+        for r in p.refs.iter().filter(|r| **r != p.path) {
+            referrers.entry(r).or_default().push(&p.path);
+        }
+    }
+    let sizes = paths
+        .iter()
+        .map(|p| (p.path.as_str(), p.nar_size))
+        .collect::<BTreeMap<_, _>>();
+    let mut by_size = paths.iter().collect::<Vec<_>>();
+    by_size.sort_by_key(|p| std::cmp::Reverse(p.nar_size));
+
+    let mut selected = BTreeSet::new();
+    let mut freed = 0;
+    for p in by_size {
+        if freed >= max_freed {
+            break;
+        }
+        let mut todo = vec![p.path.as_str()];
+        while let Some(path) = todo.pop() {
+            if selected.insert(path) {
+                freed += sizes[path];
+                todo.extend(referrers.get(path).into_iter().flatten());
+            }
+        }
+    }
+    let selected = selected
+        .into_iter()
+        .map(str::to_owned)
+        .collect::<BTreeSet<_>>();
+    paths
+        .into_iter()
+        .filter(|p| selected.contains(&p.path))
+        .collect()
+}
+
+fn query_gc_plan(largest: usize, max_freed: Option<u64>) -> anyhow::Result<GcPlan> {
+    let mut paths = libnixstore::query_dead_paths()?
+        .into_iter()
+        // paths can disappear while we are querying them, e.g. by a concurrent gc run
+        .filter_map(|path| {
+            let info = libnixstore::query_path_info(&path, Radix::default()).ok()?;
+            Some(DeadPath {
+                path,
+                nar_size: info.size,
+                refs: info.refs,
+            })
+        })
+        .collect::<Vec<_>>();
+    if let Some(max_freed) = max_freed {
+        paths = bound_freed(paths, max_freed);
+    }
+    let total_nar_size = paths.iter().map(|p| p.nar_size).sum();
+
+    let mut by_size = paths.clone();
+    by_size.sort_by_key(|p| std::cmp::Reverse(p.nar_size));
+    by_size.truncate(largest);
+
+    Ok(GcPlan {
+        paths,
+        total_nar_size,
+        largest: by_size,
+    })
+}
+
+pub(crate) async fn gc_plan(
+    req: HttpRequest,
+    params: web::Query<GcPlanParams>,
+    settings: web::Data<Config>,
+) -> Result<HttpResponse, Box<dyn Error>> {
+    if let Some(res) = check_token(&req, &settings) {
+        return Ok(res);
+    }
+    // finding dead paths walks the whole store, so keep it off the worker thread
+    let (largest, max_freed) = (params.largest, params.max_freed);
+    let plan = web::block(move || query_gc_plan(largest, max_freed)).await??;
+    Ok(HttpResponse::Ok()
+        .insert_header(cache_control_no_store())
+        .json(plan))
+}
+
+/// GC root names become file names in `gc_roots_dir`, so only allow a safe subset.
+fn valid_root_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 200
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+}
+
+fn bad_request(message: &str) -> HttpResponse {
+    HttpResponse::BadRequest()
+        .insert_header(cache_control_no_store())
+        .body(message.to_owned())
+}
+
+/// Returns the directory GC roots are created in, or the response to send if pinning is disabled.
+fn gc_roots_dir<'a>(req: &HttpRequest, settings: &'a Config) -> Result<&'a str, HttpResponse> {
+    if let Some(res) = check_token(req, settings) {
+        return Err(res);
+    }
+    settings.gc_roots_dir.as_deref().ok_or_else(|| {
+        HttpResponse::NotFound()
+            .insert_header(cache_control_no_store())
+            .finish()
+    })
+}
+
+fn list_gc_roots(dir: &str) -> anyhow::Result<Vec<GcRoot>> {
+    let mut roots = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let Ok(target) = std::fs::read_link(entry.path()) else {
+            continue;
+        };
+        roots.push(GcRoot {
+            name: entry.file_name().to_string_lossy().into_owned(),
+            path: target.to_string_lossy().into_owned(),
+        });
+    }
+    roots.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(roots)
+}
+
+pub(crate) async fn list_pins(
+    req: HttpRequest,
+    settings: web::Data<Config>,
+) -> Result<HttpResponse, Box<dyn Error>> {
+    let dir = match gc_roots_dir(&req, &settings) {
+        Ok(dir) => dir.to_owned(),
+        Err(res) => return Ok(res),
+    };
+    let roots = web::block(move || list_gc_roots(&dir)).await??;
+    Ok(HttpResponse::Ok()
+        .insert_header(cache_control_no_store())
+        .json(roots))
+}
+
+pub(crate) async fn pin(
+    req: HttpRequest,
+    name: web::Path<String>,
+    body: web::Json<PinRequest>,
+    settings: web::Data<Config>,
+) -> Result<HttpResponse, Box<dyn Error>> {
+    let dir = match gc_roots_dir(&req, &settings) {
+        Ok(dir) => dir.to_owned(),
+        Err(res) => return Ok(res),
+    };
+    let name = name.into_inner();
+    if !valid_root_name(&name) {
+        return Ok(bad_request("invalid gc root name"));
+    }
+    let Some(store_path) = hash_part(&body.path).and_then(nixhash) else {
+        return Ok(HttpResponse::NotFound()
+            .insert_header(cache_control_no_store())
+            .body("store path not found"));
+    };
+
+    let gc_root = Path::new(&dir).join(&name);
+    let root = {
+        let store_path = store_path.clone();
+        // addPermRoot replaces an existing root by renaming the new symlink over it, so there is
+        // no moment in which neither the old nor the new path is rooted
+        web::block(move || libnixstore::add_perm_root(&store_path, &gc_root.to_string_lossy()))
+            .await??
+    };
+    log::info!("pinned {store_path} as gc root {root}");
+    Ok(HttpResponse::Ok()
+        .insert_header(cache_control_no_store())
+        .json(GcRoot {
+            name,
+            path: store_path,
+        }))
+}
+
+pub(crate) async fn unpin(
+    req: HttpRequest,
+    name: web::Path<String>,
+    settings: web::Data<Config>,
+) -> Result<HttpResponse, Box<dyn Error>> {
+    let dir = match gc_roots_dir(&req, &settings) {
+        Ok(dir) => dir.to_owned(),
+        Err(res) => return Ok(res),
+    };
+    let name = name.into_inner();
+    if !valid_root_name(&name) {
+        return Ok(bad_request("invalid gc root name"));
+    }
+    let gc_root = Path::new(&dir).join(&name);
+    match std::fs::symlink_metadata(&gc_root) {
+        Ok(m) if m.file_type().is_symlink() => {}
+        _ => {
+            return Ok(HttpResponse::NotFound()
+                .insert_header(cache_control_no_store())
+                .body("gc root not found"))
+        }
+    }
+    tokio::fs::remove_file(&gc_root).await?;
+    log::info!("removed gc root {}", gc_root.display());
+    Ok(HttpResponse::NoContent()
+        .insert_header(cache_control_no_store())
+        .finish())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_valid_root_name() {
+        assert!(valid_root_name("ci-main_1.2"));
+        assert!(!valid_root_name(""));
+        assert!(!valid_root_name(".."));
+        assert!(!valid_root_name(".hidden"));
+        assert!(!valid_root_name("a/b"));
+        assert!(!valid_root_name("a b"));
+        assert!(!valid_root_name(&"a".repeat(201)));
+    }
+
+    #[test]
+    fn test_bound_freed() {
+        let dead = |path: &str, nar_size, refs: &[&str]| DeadPath {
+            path: path.to_owned(),
+            nar_size,
+            refs: refs.iter().map(|r| r.to_string()).collect(),
+        };
+        let paths = vec![
+            dead("a", 10, &["a", "c"]),
+            dead("b", 40, &[]),
+            dead("c", 100, &["live"]),
+            dead("d", 5, &["b"]),
+        ];
+        let bounded = |max_freed| {
+            bound_freed(paths.clone(), max_freed)
+                .into_iter()
+                .map(|p| p.path)
+                .collect::<Vec<_>>()
+        };
+        assert!(bounded(0).is_empty());
+        // deleting c requires deleting its referrer a first
+        assert_eq!(bounded(1), ["a", "c"]);
+        assert_eq!(bounded(110), ["a", "c"]);
+        assert_eq!(bounded(111), ["a", "b", "c", "d"]);
+        assert_eq!(bounded(u64::MAX), ["a", "b", "c", "d"]);
+    }
+
+    #[test]
+    fn test_token_matches() {
+        assert!(token_matches("secret", "secret"));
+        assert!(!token_matches("secret", "secreT"));
+        assert!(!token_matches("secret", "secret2"));
+        assert!(!token_matches("secret", ""));
+    }
+}
@@ -0,0 +1,300 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+
+use actix_web::{web, HttpRequest, HttpResponse};
+use anyhow::Context;
+use base64::{engine::general_purpose, Engine};
+use libnixstore::Radix;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::closure::query_closure;
+use crate::config::Config;
+use crate::hash::{hash_part, NixHash32};
+use crate::{admin, cache_control_no_store, narinfo, nixhash};
+
+/// Upper bound for the number of paths in a single `/api/v1/paths` request.
+const MAX_PATHS: usize = 1000;
+
+#[derive(Debug, Deserialize)]
+pub struct PathsRequest {
+    /// Store paths or their hash parts.
+    paths: Vec<String>,
+}
+
+fn default_limit() -> usize {
+    100
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TopPathsParams {
+    #[serde(default = "default_limit")]
+    limit: usize,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PathInfoParams {
+    #[serde(default, deserialize_with = "crate::deserialize_flag")]
+    closure: bool,
+}
+
+/// A path in the format of `nix path-info --json`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PathInfoJson {
+    deriver: Option<String>,
+    nar_hash: String,
+    nar_size: u64,
+    references: Vec<String>,
+    registration_time: i64,
+    signatures: Vec<String>,
+    ca: Option<String>,
+    ultimate: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    closure_size: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct StatsResponse {
+    version: &'static str,
+    store_dir: String,
+    uptime_secs: u64,
+    requests: u64,
+    narinfo_hits: u64,
+    narinfo_misses: u64,
+    nar_requests: u64,
+    errors: u64,
+}
+
+fn not_found(message: &str) -> HttpResponse {
+    HttpResponse::NotFound()
+        .insert_header(cache_control_no_store())
+        .json(json!({ "error": message }))
+}
+
+/// Converts a base16 hash like `sha256:abcd...` to SRI form like `sha256-q80=`.
+fn to_sri(hash: &str) -> anyhow::Result<String> {
+    let (algo, hex) = hash.split_once(':').context("hash without algorithm")?;
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2).unwrap_or("x"), 16))
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("invalid base16 hash '{hash}'"))?;
+    Ok(format!(
+        "{algo}-{}",
+        general_purpose::STANDARD.encode(bytes)
+    ))
+}
+
+/// Adds the closure of `path` to `closures`, reusing the closures of its references that are
+/// already there. Store paths can only refer to themselves, so there are no other cycles.
+fn visit_closure<'a>(
+    path: &'a str,
+    paths: &'a BTreeMap<String, libnixstore::PathInfo>,
+    closures: &mut BTreeMap<&'a str, BTreeSet<&'a str>>,
+) {
+    if closures.contains_key(path) {
+        return;
+    }
+    let mut closure = BTreeSet::from([path]);
+    for r in paths.get(path).into_iter().flat_map(|info| &info.refs) {
+        if r != path {
+            visit_closure(r, paths, closures);
+            closure.extend(&closures[r.as_str()]);
+        }
+    }
+    closures.insert(path, closure);
+}
+
+/// Sums the nar sizes of the closure of every path in `paths`, which contains whole closures.
+fn closure_sizes(paths: &BTreeMap<String, libnixstore::PathInfo>) -> BTreeMap<&str, u64> {
+    let mut closures = BTreeMap::new();
+    for path in paths.keys() {
+        visit_closure(path, paths, &mut closures);
+    }
+    closures
+        .into_iter()
+        .map(|(path, closure)| {
+            let size = closure
+                .iter()
+                .filter_map(|p| paths.get(*p))
+                .map(|info| info.size)
+                .sum();
+            (path, size)
+        })
+        .collect()
+}
+
+fn query_path_infos(
+    store_path: &str,
+    closure: bool,
+) -> anyhow::Result<BTreeMap<String, PathInfoJson>> {
+    let infos = if closure {
+        query_closure(store_path, Radix::Base16)?
+    } else {
+        BTreeMap::from([(
+            store_path.to_owned(),
+            libnixstore::query_path_info(store_path, Radix::Base16)?,
+        )])
+    };
+    let closure_sizes = closure.then(|| closure_sizes(&infos));
+    infos
+        .iter()
+        .map(|(path, info)| {
+            let mut references = info.refs.clone();
+            references.sort();
+            Ok((
+                path.clone(),
+                PathInfoJson {
+                    deriver: info.drv.clone(),
+                    nar_hash: to_sri(&info.narhash)?,
+                    nar_size: info.size,
+                    references,
+                    registration_time: info.time,
+                    signatures: info.sigs.clone(),
+                    ca: info.ca.clone(),
+                    ultimate: info.ultimate,
+                    closure_size: closure_sizes.as_ref().map(|sizes| sizes[path.as_str()]),
+                },
+            ))
+        })
+        .collect()
+}
+
+pub(crate) async fn path_info(
+    hash: NixHash32,
+    params: web::Query<PathInfoParams>,
+) -> Result<HttpResponse, Box<dyn Error>> {
+    let Some(store_path) = nixhash(hash.as_str()) else {
+        return Ok(not_found("store path not found"));
+    };
+    let closure = params.closure;
+    let infos = web::block(move || query_path_infos(&store_path, closure)).await??;
+    Ok(HttpResponse::Ok()
+        .insert_header(cache_control_no_store())
+        .json(infos))
+}
+
+pub(crate) async fn narinfo(
+    hash: NixHash32,
+    req: HttpRequest,
+    settings: web::Data<Config>,
+) -> Result<HttpResponse, Box<dyn Error>> {
+    let Some(store_path) = nixhash(hash.as_str()) else {
+        return Ok(not_found("store path not found"));
+    };
+    let host = settings.for_request(&req);
+    let narinfo = narinfo::query_narinfo(&store_path, hash.as_str(), host.secret_keys)?;
+    Ok(HttpResponse::Ok()
+        .insert_header(cache_control_no_store())
+        .json(narinfo))
+}
+
+pub(crate) async fn paths(body: web::Json<PathsRequest>) -> Result<HttpResponse, Box<dyn Error>> {
+    let paths = body.into_inner().paths;
+    if paths.len() > MAX_PATHS {
+        return Ok(HttpResponse::PayloadTooLarge()
+            .insert_header(cache_control_no_store())
+            .json(json!({ "error": format!("at most {MAX_PATHS} paths per request") })));
+    }
+    let result = web::block(move || {
+        paths
+            .into_iter()
+            .map(|path| {
+                let valid = hash_part(&path).and_then(nixhash).is_some();
+                (path, valid)
+            })
+            .collect::<BTreeMap<_, _>>()
+    })
+    .await?;
+    Ok(HttpResponse::Ok()
+        .insert_header(cache_control_no_store())
+        .json(result))
+}
+
+pub(crate) async fn stats(settings: web::Data<Config>) -> Result<HttpResponse, Box<dyn Error>> {
+    let stats = &settings.stats;
+    Ok(HttpResponse::Ok()
+        .insert_header(cache_control_no_store())
+        .json(StatsResponse {
+            version: crate::CARGO_VERSION,
+            store_dir: libnixstore::get_store_dir(),
+            uptime_secs: stats.uptime().as_secs(),
+            requests: stats.requests(),
+            narinfo_hits: stats.narinfo_hits(),
+            narinfo_misses: stats.narinfo_misses(),
+            nar_requests: stats.nar_requests(),
+            errors: stats.errors(),
+        }))
+}
+
+pub(crate) async fn top_paths(
+    req: HttpRequest,
+    params: web::Query<TopPathsParams>,
+    settings: web::Data<Config>,
+) -> Result<HttpResponse, Box<dyn Error>> {
+    // reveals what clients download, so it's private unless configured otherwise
+    if !settings.stats_config.public {
+        if let Some(res) = admin::check_token(&req, &settings) {
+            return Ok(res);
+        }
+    }
+    let limit = params.limit.min(MAX_PATHS);
+    let top = web::block(move || settings.stats.top_paths(limit)).await??;
+    Ok(HttpResponse::Ok()
+        .insert_header(cache_control_no_store())
+        .json(top))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_closure_flag() {
+        let closure = |query| web::Query::<PathInfoParams>::from_query(query).map(|p| p.closure);
+        assert!(!closure("").unwrap());
+        assert!(closure("closure=1").unwrap());
+        assert!(closure("closure=true").unwrap());
+        assert!(!closure("closure=0").unwrap());
+        assert!(!closure("closure=false").unwrap());
+        assert!(closure("closure=").is_err());
+        assert!(closure("closure=yes").is_err());
+    }
+
+    #[test]
+    fn test_to_sri() {
+        assert_eq!(
+            to_sri("sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855")
+                .unwrap(),
+            "sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU="
+        );
+        assert!(to_sri("e3b0").is_err());
+        assert!(to_sri("sha256:zz").is_err());
+        assert!(to_sri("sha256:abc").is_err());
+    }
+
+    #[test]
+    fn test_closure_sizes() {
+        let info = |size, refs: &[&str]| libnixstore::PathInfo {
+            drv: None,
+            narhash: String::new(),
+            time: 0,
+            size,
+            refs: refs.iter().map(|r| r.to_string()).collect(),
+            sigs: vec![],
+            ca: None,
+            ultimate: false,
+        };
+        let paths = BTreeMap::from([
+            ("a".to_owned(), info(1, &["a", "b", "c"])),
+            ("b".to_owned(), info(2, &["c", "d"])),
+            ("c".to_owned(), info(4, &["d"])),
+            ("d".to_owned(), info(8, &[])),
+        ]);
+        assert_eq!(
+            closure_sizes(&paths),
+            BTreeMap::from([("a", 15), ("b", 14), ("c", 12), ("d", 8)])
+        );
+    }
+}
@@ -1,23 +1,349 @@
 use std::error::Error;
+use std::io::{self, Write};
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
-use actix_web::{http, web, HttpResponse};
+use actix_web::web::Bytes;
+use actix_web::{http, web, HttpRequest, HttpResponse};
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+use tokio::sync::mpsc::Sender;
+use tokio::task;
 
+use crate::compression::{self, Compressor};
+use crate::config::Config;
+use crate::store::Store;
 use crate::{cache_control_max_age_1y, cache_control_no_store, nixhash, some_or_404};
 
+/// How often a followed log file is checked for new data.
+const FOLLOW_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Stop following a log that didn't grow for this long, for builds that can't be told to be
+/// over, e.g. ones whose outputs aren't known yet or that left a stale lock file behind.
+const FOLLOW_IDLE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Debug, Deserialize)]
+pub struct Param {
+    #[serde(default, deserialize_with = "crate::deserialize_flag")]
+    follow: bool,
+}
+
 fn query_drv_path(drv: &str) -> Option<String> {
     nixhash(if drv.len() > 32 { &drv[0..32] } else { drv })
 }
 
-pub(crate) async fn get(drv: web::Path<String>) -> Result<HttpResponse, Box<dyn Error>> {
+/// Returns the log file for `drv_path` in the nix log directory, if there is one.
+/// Compressed logs may use any of the formats in `LogFormat`.
+fn find_log_file(drv_path: &str) -> Option<PathBuf> {
+    let base_name = Path::new(drv_path).file_name()?.to_str()?;
+    let drvs_dir = Path::new(&libnixstore::get_log_dir()).join("drvs");
+    // same lookup order as nix' LocalFSStore::getBuildLogExact
+    [
+        drvs_dir.join(&base_name[0..2]).join(&base_name[2..]),
+        drvs_dir.join(base_name),
+    ]
+    .into_iter()
+    .flat_map(|p| {
+        ["", ".bz2", ".zst"].map(|ext| {
+            let mut candidate = p.clone().into_os_string();
+            candidate.push(ext);
+            PathBuf::from(candidate)
+        })
+    })
+    .find(|p| p.is_file())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LogFormat {
+    Plain,
+    Bzip2,
+    Zstd,
+}
+
+impl LogFormat {
+    /// Detects the format from the magic bytes of the file. Files that are too short to tell,
+    /// e.g. logs of builds that just started, are recognized by their extension.
+    fn detect(log_file: &Path) -> io::Result<Self> {
+        let mut magic = [0u8; 4];
+        let mut file = std::fs::File::open(log_file)?;
+        let n = std::io::Read::read(&mut file, &mut magic)?;
+        Ok(match &magic[0..n] {
+            [b'B', b'Z', b'h', ..] => LogFormat::Bzip2,
+            [0x28, 0xb5, 0x2f, 0xfd] => LogFormat::Zstd,
+            _ if n < magic.len() => match log_file.extension().and_then(|e| e.to_str()) {
+                Some("bz2") => LogFormat::Bzip2,
+                Some("zst") => LogFormat::Zstd,
+                _ => LogFormat::Plain,
+            },
+            _ => LogFormat::Plain,
+        })
+    }
+
+    /// The value for the Content-Encoding header when sending the file as is. bzip2 is not an
+    /// HTTP content coding, so such logs are always decompressed.
+    fn content_encoding(&self) -> Option<&'static str> {
+        match self {
+            LogFormat::Plain | LogFormat::Bzip2 => None,
+            LogFormat::Zstd => Some("zstd"),
+        }
+    }
+
+    fn decode_file(&self, log_file: &Path) -> io::Result<Vec<u8>> {
+        let file = std::fs::File::open(log_file)?;
+        let mut log = Vec::new();
+        match self {
+            LogFormat::Plain => std::io::Read::read_to_end(&mut &file, &mut log)?,
+            LogFormat::Bzip2 => {
+                std::io::Read::read_to_end(&mut bzip2::read::BzDecoder::new(file), &mut log)?
+            }
+            LogFormat::Zstd => {
+                std::io::Read::read_to_end(&mut zstd::stream::read::Decoder::new(file)?, &mut log)?
+            }
+        };
+        Ok(log)
+    }
+}
+
+/// Decodes log files incrementally, so logs can be streamed while nix is still writing them.
+enum LogDecoder {
+    Plain,
+    Bzip2(bzip2::write::BzDecoder<Vec<u8>>),
+    Zstd(zstd::stream::write::Decoder<'static, Vec<u8>>),
+}
+
+impl LogDecoder {
+    fn new(format: LogFormat) -> io::Result<Self> {
+        Ok(match format {
+            LogFormat::Plain => LogDecoder::Plain,
+            LogFormat::Bzip2 => LogDecoder::Bzip2(bzip2::write::BzDecoder::new(Vec::new())),
+            LogFormat::Zstd => LogDecoder::Zstd(zstd::stream::write::Decoder::new(Vec::new())?),
+        })
+    }
+
+    fn decode(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            LogDecoder::Plain => Ok(data.to_vec()),
+            LogDecoder::Bzip2(d) => {
+                d.write_all(data)?;
+                Ok(std::mem::take(d.get_mut()))
+            }
+            LogDecoder::Zstd(d) => {
+                d.write_all(data)?;
+                d.flush()?;
+                Ok(std::mem::take(d.get_mut()))
+            }
+        }
+    }
+}
+
+/// A build is finished once all outputs of its derivation are valid.
+fn build_finished(drv_path: &str) -> bool {
+    match libnixstore::derivation_from_path(drv_path) {
+        Ok(drv) => drv
+            .outputs
+            .values()
+            .all(|out| out.as_deref().is_some_and(libnixstore::is_valid_path)),
+        // nothing to wait for
+        Err(_) => true,
+    }
+}
+
+/// Calls `flock` on `file`, the kind of lock nix takes on its lock files. With `LOCK_NB`, fails
+/// with `WouldBlock` if another open file holds a conflicting lock.
+fn flock(file: &std::fs::File, operation: libc::c_int) -> io::Result<()> {
+    // SAFETY: the descriptor stays open while `file` is borrowed
+    if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Whether nix holds the lock file of `output`, which it does while building it.
+fn output_locked(store: &Store, output: &str) -> bool {
+    let mut lock_file = store.get_real_path(output).into_os_string();
+    lock_file.push(".lock");
+    match std::fs::File::open(&lock_file) {
+        // nix removes the lock file once the build is over, successful or not
+        Err(e) if e.kind() == io::ErrorKind::NotFound => false,
+        // only readable by the nix daemon, but it exists
+        Err(_) => true,
+        Ok(file) => flock(&file, libc::LOCK_SH | libc::LOCK_NB)
+            .is_err_and(|e| e.kind() == io::ErrorKind::WouldBlock),
+    }
+}
+
+/// A build may still be running while one of the outputs of its derivation is locked. Outputs
+/// that aren't known before the build, as with floating content-addressed derivations, are
+/// assumed to be building.
+fn build_running(store: &Store, drv_path: &str) -> bool {
+    match libnixstore::derivation_from_path(drv_path) {
+        Ok(drv) => drv.outputs.values().any(|out| match out {
+            Some(out) => output_locked(store, out),
+            None => true,
+        }),
+        // nothing to wait for
+        Err(_) => false,
+    }
+}
+
+async fn follow_log(
+    drv_path: &str,
+    log_file: &Path,
+    settings: web::Data<Config>,
+    tx: &Sender<Result<Bytes, io::Error>>,
+) -> Result<()> {
+    let mut file = File::open(log_file)
+        .await
+        .with_context(|| format!("Failed to open log file: {}", log_file.display()))?;
+    let format = LogFormat::detect(log_file)
+        .with_context(|| format!("Failed to read log file: {}", log_file.display()))?;
+    // bzip2 logs can only be decoded in whole blocks of 900 kB, so their lines show up in bursts
+    let mut decoder = LogDecoder::new(format).context("Failed to create decoder")?;
+    let mut buf = vec![0; 16384];
+    let mut idle = Duration::ZERO;
+    let mut stopped = false;
+
+    loop {
+        let n = file
+            .read(&mut buf)
+            .await
+            .with_context(|| format!("Failed to read log file: {}", log_file.display()))?;
+        if n > 0 {
+            idle = Duration::ZERO;
+            let data = decoder
+                .decode(&buf[0..n])
+                .with_context(|| format!("Failed to decode log file: {}", log_file.display()))?;
+            if !data.is_empty() && tx.send(Ok(Bytes::from(data))).await.is_err() {
+                // client went away
+                return Ok(());
+            }
+            continue;
+        }
+        if stopped || idle >= FOLLOW_IDLE_TIMEOUT {
+            return Ok(());
+        }
+        // read once more after the build stopped to get the last lines written before, which
+        // also covers failed builds, whose outputs never become valid
+        let drv = drv_path.to_owned();
+        let settings = settings.clone();
+        stopped = !web::block(move || build_running(&settings.store, &drv)).await?;
+        if !stopped {
+            tokio::time::sleep(FOLLOW_POLL_INTERVAL).await;
+            idle += FOLLOW_POLL_INTERVAL;
+        }
+    }
+}
+
+/// Serves a log from the local log directory, passing it through compressed if the client
+/// accepts the encoding it is stored in, and decompressing it otherwise. Logs of builds that are
+/// still running are not cached, as they are incomplete.
+async fn local_log(
+    req: &HttpRequest,
+    drv_path: String,
+    log_file: PathBuf,
+    compressor: Arc<Compressor>,
+) -> Result<HttpResponse, Box<dyn Error>> {
+    let format = LogFormat::detect(&log_file)?;
+    let finished = web::block(move || build_finished(&drv_path)).await?;
+    let accept_encoding = req
+        .headers()
+        .get(http::header::ACCEPT_ENCODING)
+        .and_then(|v| v.to_str().ok());
+
+    let mut res = HttpResponse::Ok();
+    res.insert_header(http::header::ContentType(mime::TEXT_PLAIN_UTF_8))
+        .insert_header((http::header::VARY, "Accept-Encoding"));
+    if finished {
+        res.insert_header(cache_control_max_age_1y());
+    } else {
+        res.insert_header(cache_control_no_store());
+    }
+
+    if let Some(encoding) = format.content_encoding() {
+        if accept_encoding.is_some_and(|a| compression::accepts(a, encoding)) {
+            let log = tokio::fs::read(&log_file).await?;
+            return Ok(res
+                .insert_header((http::header::CONTENT_ENCODING, encoding))
+                .body(log));
+        }
+    }
+
+    let log = web::block(move || format.decode_file(&log_file)).await??;
+    if let Some(encoding) = compressor.negotiate(accept_encoding) {
+        let (tx, rx) = tokio::sync::mpsc::channel::<Result<Bytes, io::Error>>(1);
+        tx.send(Ok(Bytes::from(log))).await?;
+        drop(tx);
+        return Ok(res
+            .insert_header((http::header::CONTENT_ENCODING, encoding.name()))
+            .streaming(compressor.compress(encoding, rx)));
+    }
+    Ok(res.body(log))
+}
+
+pub(crate) async fn get(
+    drv: web::Path<String>,
+    param: web::Query<Param>,
+    req: HttpRequest,
+    settings: web::Data<Config>,
+) -> Result<HttpResponse, Box<dyn Error>> {
     let drv_path = some_or_404!(query_drv_path(&drv));
-    if libnixstore::is_valid_path(&drv_path) {
-        let build_log = some_or_404!(libnixstore::get_build_log(&drv_path));
+    if !libnixstore::is_valid_path(&drv_path) {
+        return Ok(HttpResponse::NotFound()
+            .insert_header(cache_control_no_store())
+            .finish());
+    }
+
+    if param.follow {
+        let log_file = some_or_404!(find_log_file(&drv_path));
+        let (tx, rx) = tokio::sync::mpsc::channel::<Result<Bytes, io::Error>>(16);
+        task::spawn(async move {
+            if let Err(err) = follow_log(&drv_path, &log_file, settings, &tx).await {
+                log::error!("Error following build log of {}: {:?}", drv_path, err);
+                let _ = tx.send(Err(io::Error::other("reading log failed"))).await;
+            }
+        });
         return Ok(HttpResponse::Ok()
             .insert_header(http::header::ContentType(mime::TEXT_PLAIN_UTF_8))
-            .insert_header(cache_control_max_age_1y())
-            .body(build_log));
+            .insert_header(cache_control_no_store())
+            // tell nginx to pass lines through as they come in
+            .insert_header(("X-Accel-Buffering", "no"))
+            .streaming(tokio_stream::wrappers::ReceiverStream::new(rx)));
+    }
+
+    if let Some(log_file) = find_log_file(&drv_path) {
+        return local_log(&req, drv_path, log_file, settings.compressor.clone()).await;
+    }
+
+    // not built locally, but a substituter might have it
+    let build_log = some_or_404!(libnixstore::get_build_log(&drv_path));
+    Ok(HttpResponse::Ok()
+        .insert_header(http::header::ContentType(mime::TEXT_PLAIN_UTF_8))
+        .insert_header(cache_control_max_age_1y())
+        .body(build_log))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_output_locked() {
+        let dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let store = Store::default();
+        let output = dir.path().join("out");
+        let output = output.to_str().unwrap();
+        let lock_file = dir.path().join("out.lock");
+        assert!(!output_locked(&store, output));
+
+        let lock = std::fs::File::create(&lock_file).unwrap();
+        assert!(!output_locked(&store, output));
+        flock(&lock, libc::LOCK_EX).unwrap();
+        assert!(output_locked(&store, output));
+        drop(lock);
+        assert!(!output_locked(&store, output));
     }
-    Ok(HttpResponse::NotFound()
-        .insert_header(cache_control_no_store())
-        .finish())
 }
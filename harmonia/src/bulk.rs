@@ -0,0 +1,302 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+
+use actix_web::web::Bytes;
+use actix_web::{http, web, HttpRequest, HttpResponse};
+use anyhow::{bail, Context, Result};
+use libnixstore::Radix;
+use serde::Deserialize;
+use tokio::sync::mpsc::{self, Sender};
+use tokio::task;
+
+use crate::cache_control_no_store;
+use crate::closure::query_closure;
+use crate::config::Config;
+use crate::hash::hash_part;
+use crate::nar::{dump_path, ThreadSafeError};
+use crate::narinfo::query_narinfo;
+
+/// Upper bound for the number of paths requested at once.
+const MAX_PATHS: usize = 1000;
+
+fn default_closure() -> bool {
+    true
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BulkRequest {
+    /// Store paths or their hash parts.
+    paths: Vec<String>,
+    /// Also send everything the paths reference, which the receiving store needs to add them.
+    #[serde(default = "default_closure")]
+    closure: bool,
+}
+
+/// Serializes values the way the nix worker protocol does.
+#[derive(Default)]
+struct WireWriter {
+    buf: Vec<u8>,
+}
+
+impl WireWriter {
+    fn num(&mut self, n: u64) -> &mut Self {
+        self.buf.extend_from_slice(&n.to_le_bytes());
+        self
+    }
+
+    fn string(&mut self, s: &str) -> &mut Self {
+        self.num(s.len() as u64);
+        self.buf.extend_from_slice(s.as_bytes());
+        let padding = (8 - s.len() % 8) % 8;
+        self.buf.extend_from_slice(&[0; 8][..padding]);
+        self
+    }
+
+    fn strings<'a>(&mut self, strings: impl ExactSizeIterator<Item = &'a String>) -> &mut Self {
+        self.num(strings.len() as u64);
+        for s in strings {
+            self.string(s);
+        }
+        self
+    }
+}
+
+/// A path as read by `Store::addMultipleToStore`: a `ValidPathInfo` followed by its nar.
+struct BulkPath {
+    store_path: String,
+    info: libnixstore::PathInfo,
+    sigs: Vec<String>,
+}
+
+impl BulkPath {
+    fn write_info(&self, w: &mut WireWriter) {
+        let nar_hash = self
+            .info
+            .narhash
+            .split_once(':')
+            .map_or(self.info.narhash.as_str(), |(_, hash)| hash);
+        let mut refs = self.info.refs.clone();
+        refs.sort();
+        let mut sigs = self.sigs.clone();
+        sigs.sort();
+        w.string(&self.store_path)
+            .string(self.info.drv.as_deref().unwrap_or(""))
+            .string(nar_hash)
+            .strings(refs.iter())
+            .num(self.info.time as u64)
+            .num(self.info.size)
+            // ultimate, only meaningful for the store that built the path
+            .num(0)
+            .strings(sigs.iter())
+            .string(self.info.ca.as_deref().unwrap_or(""));
+    }
+}
+
+/// Orders `paths` so that every path comes after the paths it references.
+fn topo_sort(paths: &BTreeMap<String, libnixstore::PathInfo>) -> Vec<String> {
+    fn visit(
+        path: &str,
+        paths: &BTreeMap<String, libnixstore::PathInfo>,
+        visited: &mut BTreeSet<String>,
+        sorted: &mut Vec<String>,
+    ) {
+        if !visited.insert(path.to_owned()) {
+            return;
+        }
+        if let Some(info) = paths.get(path) {
+            for r in &info.refs {
+                if r != path {
+                    visit(r, paths, visited, sorted);
+                }
+            }
+            sorted.push(path.to_owned());
+        }
+    }
+
+    let mut visited = BTreeSet::new();
+    let mut sorted = Vec::with_capacity(paths.len());
+    for path in paths.keys() {
+        visit(path, paths, &mut visited, &mut sorted);
+    }
+    sorted
+}
+
+fn query_paths(
+    requested: &[String],
+    closure: bool,
+    secret_keys: &Vec<String>,
+) -> Result<Option<Vec<BulkPath>>> {
+    let mut infos = BTreeMap::new();
+    for path in requested {
+        let Some(store_path) = hash_part(path).and_then(crate::nixhash) else {
+            return Ok(None);
+        };
+        if closure {
+            // the worker protocol sends nar hashes in base16
+            infos.extend(query_closure(&store_path, Radix::Base16)?);
+        } else {
+            let info = libnixstore::query_path_info(&store_path, Radix::Base16)
+                .with_context(|| format!("Couldn't query path info of {store_path}"))?;
+            infos.insert(store_path, info);
+        }
+    }
+
+    let mut paths = Vec::with_capacity(infos.len());
+    for store_path in topo_sort(&infos) {
+        let info = infos.remove(&store_path).context("path vanished")?;
+        let hash =
+            hash_part(&store_path).with_context(|| format!("Invalid store path {store_path}"))?;
+        let sigs = query_narinfo(&store_path, hash, secret_keys)
+            .map_err(|e| anyhow::anyhow!("Couldn't sign {store_path}: {e}"))?
+            .sigs;
+        paths.push(BulkPath {
+            store_path,
+            info,
+            sigs,
+        });
+    }
+    Ok(Some(paths))
+}
+
+async fn send_frame(tx: &Sender<Result<Bytes, ThreadSafeError>>, data: Bytes) -> Result<()> {
+    if data.is_empty() {
+        return Ok(());
+    }
+    let len = Bytes::copy_from_slice(&(data.len() as u64).to_le_bytes());
+    if tx.send(Ok(len)).await.is_err() || tx.send(Ok(data)).await.is_err() {
+        bail!("client went away");
+    }
+    Ok(())
+}
+
+/// Writes the paths in the framed format the daemon reads after `wopAddMultipleToStore`, so a
+/// client can forward the response body to its daemon as is.
+async fn write_paths(
+    settings: web::Data<Config>,
+    paths: Vec<BulkPath>,
+    tx: &Sender<Result<Bytes, ThreadSafeError>>,
+) -> Result<()> {
+    let mut w = WireWriter::default();
+    // sent on its own, so the count is there even without any paths
+    w.num(paths.len() as u64);
+    send_frame(tx, Bytes::from(std::mem::take(&mut w.buf))).await?;
+    for path in paths {
+        path.write_info(&mut w);
+        send_frame(tx, Bytes::from(std::mem::take(&mut w.buf))).await?;
+
+        let (nar_tx, mut nar_rx) = mpsc::channel(1000);
+        let real_path = settings.store.get_real_path(&path.store_path);
+        let dumper = task::spawn(async move { dump_path(real_path, &nar_tx).await });
+        let mut sent = 0;
+        while let Some(Ok(data)) = nar_rx.recv().await {
+            sent += data.len() as u64;
+            send_frame(tx, data).await?;
+        }
+        dumper
+            .await?
+            .with_context(|| format!("Error dumping path {}", path.store_path))?;
+        if sent != path.info.size {
+            bail!(
+                "Nar of {} has {sent} bytes, but {} are registered",
+                path.store_path,
+                path.info.size
+            );
+        }
+    }
+    // an empty frame ends the framed stream
+    if tx.send(Ok(Bytes::from_static(&[0; 8]))).await.is_err() {
+        bail!("client went away");
+    }
+    Ok(())
+}
+
+pub(crate) async fn post(
+    body: web::Json<BulkRequest>,
+    req: HttpRequest,
+    settings: web::Data<Config>,
+) -> Result<HttpResponse, Box<dyn Error>> {
+    let BulkRequest { paths, closure } = body.into_inner();
+    if paths.len() > MAX_PATHS {
+        return Ok(HttpResponse::PayloadTooLarge()
+            .insert_header(cache_control_no_store())
+            .body(format!("at most {MAX_PATHS} paths per request")));
+    }
+    let secret_keys = settings.for_request(&req).secret_keys.clone();
+    let Some(paths) = web::block(move || query_paths(&paths, closure, &secret_keys)).await?? else {
+        return Ok(HttpResponse::NotFound()
+            .insert_header(cache_control_no_store())
+            .body("missed hash"));
+    };
+
+    let (tx, rx) = mpsc::channel::<Result<Bytes, ThreadSafeError>>(16);
+    task::spawn(async move {
+        if let Err(err) = write_paths(settings, paths, &tx).await {
+            log::error!("Error sending bulk paths: {:?}", err);
+            let _ = tx.send(Err(ThreadSafeError)).await;
+        }
+    });
+    Ok(HttpResponse::Ok()
+        .insert_header((
+            http::header::CONTENT_TYPE,
+            "application/x-nix-worker-stream",
+        ))
+        .insert_header(cache_control_no_store())
+        .streaming(tokio_stream::wrappers::ReceiverStream::new(rx)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn info(refs: &[&str]) -> libnixstore::PathInfo {
+        libnixstore::PathInfo {
+            drv: None,
+            narhash: "sha256:00".into(),
+            time: 0,
+            size: 0,
+            refs: refs.iter().map(|r| r.to_string()).collect(),
+            sigs: vec![],
+            ca: None,
+            ultimate: false,
+        }
+    }
+
+    #[test]
+    fn test_topo_sort() {
+        let paths = BTreeMap::from([
+            ("a".to_owned(), info(&["a", "c"])),
+            ("b".to_owned(), info(&["a"])),
+            ("c".to_owned(), info(&[])),
+        ]);
+        assert_eq!(topo_sort(&paths), ["c", "a", "b"]);
+    }
+
+    #[tokio::test]
+    async fn test_empty_framing() -> Result<()> {
+        let settings = web::Data::new(toml::from_str::<Config>("")?);
+        let (tx, mut rx) = mpsc::channel(16);
+        write_paths(settings, vec![], &tx).await?;
+        drop(tx);
+        let mut out = Vec::new();
+        while let Some(Ok(data)) = rx.recv().await {
+            out.extend_from_slice(&data);
+        }
+        // a frame holding the count 0, then the empty frame ending the stream
+        let expected = [8u64, 0, 0].map(u64::to_le_bytes).concat();
+        assert_eq!(out, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_wire_string() {
+        let mut w = WireWriter::default();
+        w.string("abc").num(1);
+        assert_eq!(
+            w.buf,
+            [3, 0, 0, 0, 0, 0, 0, 0, b'a', b'b', b'c', 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0]
+        );
+        let mut w = WireWriter::default();
+        w.string("");
+        assert_eq!(w.buf, [0; 8]);
+    }
+}
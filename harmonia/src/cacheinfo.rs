@@ -1,18 +1,32 @@
 use std::error::Error;
 
 use crate::config;
-use actix_web::{http, web, HttpResponse};
+use actix_web::{http, web, HttpRequest, HttpResponse};
 
-pub(crate) async fn get(config: web::Data<config::Config>) -> Result<HttpResponse, Box<dyn Error>> {
+pub(crate) async fn get(
+    req: HttpRequest,
+    config: web::Data<config::Config>,
+) -> Result<HttpResponse, Box<dyn Error>> {
+    let config = config.for_request(&req);
+    let mut res = vec![
+        format!("StoreDir: {}", libnixstore::get_store_dir()),
+        format!("WantMassQuery: {}", u8::from(config.want_mass_query)),
+        format!("Priority: {}", config.priority),
+    ];
+
+    if !config.cache_info.compression.is_empty() {
+        res.push(format!(
+            "Compression: {}",
+            config.cache_info.compression.join(" ")
+        ));
+    }
+
+    for (key, value) in &config.cache_info.extra {
+        res.push(format!("{}: {}", key, value));
+    }
+
+    res.push("".into());
     Ok(HttpResponse::Ok()
         .insert_header((http::header::CONTENT_TYPE, "text/x-nix-cache-info"))
-        .body(
-            [
-                format!("StoreDir: {}", libnixstore::get_store_dir()),
-                "WantMassQuery: 1".to_owned(),
-                format!("Priority: {}", config.priority),
-                "".to_owned(),
-            ]
-            .join("\n"),
-        ))
+        .body(res.join("\n")))
 }
@@ -0,0 +1,374 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::path::PathBuf;
+
+use actix_web::web::Bytes;
+use actix_web::{http, web, HttpRequest, HttpResponse};
+use anyhow::{bail, Context, Result};
+use libnixstore::Radix;
+use serde::Serialize;
+use tokio::sync::mpsc::{self, Sender};
+use tokio::task;
+
+use crate::compression::Encoding;
+use crate::config::Config;
+use crate::hash::{hash_part, NixHash32};
+use crate::nar::{dump_path, ThreadSafeError};
+use crate::narinfo::{format_narinfo_txt, query_narinfo};
+use crate::{cache_control_max_age_1y, nixhash, some_or_404};
+
+const TAR_BLOCK_SIZE: usize = 512;
+
+#[derive(Debug, Serialize)]
+struct ManifestEntry {
+    store_path: String,
+    nar: String,
+    nar_hash: String,
+    nar_size: u64,
+    references: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct Manifest {
+    root: String,
+    store_dir: String,
+    paths: Vec<ManifestEntry>,
+}
+
+/// A path of the closure with everything written to the archive for it.
+#[derive(Debug)]
+struct ArchivePath {
+    store_path: String,
+    hash: String,
+    narinfo: String,
+    nar_hash: String,
+    nar_size: u64,
+    references: Vec<String>,
+}
+
+/// Returns the runtime closure of `store_path`, sorted by store path. `radix` is used for the nar
+/// hashes.
+pub(crate) fn query_closure(
+    store_path: &str,
+    radix: Radix,
+) -> Result<BTreeMap<String, libnixstore::PathInfo>> {
+    let mut closure = BTreeMap::new();
+    let mut todo = BTreeSet::from([store_path.to_owned()]);
+    while let Some(path) = todo.pop_first() {
+        let info = libnixstore::query_path_info(&path, radix)
+            .with_context(|| format!("Couldn't query path info of {path}"))?;
+        for r in &info.refs {
+            if !closure.contains_key(r) && *r != path {
+                todo.insert(r.clone());
+            }
+        }
+        closure.insert(path, info);
+    }
+    Ok(closure)
+}
+
+/// Builds a tar header with fixed metadata, so the same closure always results in the same
+/// archive.
+fn tar_header(name: &str, size: u64) -> Result<[u8; TAR_BLOCK_SIZE]> {
+    let mut header = tar::Header::new_ustar();
+    header.set_path(name)?;
+    header.set_size(size);
+    header.set_mode(0o444);
+    header.set_mtime(0);
+    header.set_uid(0);
+    header.set_gid(0);
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_cksum();
+    let mut block = [0; TAR_BLOCK_SIZE];
+    block.copy_from_slice(header.as_bytes());
+    Ok(block)
+}
+
+fn tar_padding(size: u64) -> &'static [u8] {
+    const ZEROS: [u8; TAR_BLOCK_SIZE] = [0; TAR_BLOCK_SIZE];
+    let rest = (size % TAR_BLOCK_SIZE as u64) as usize;
+    if rest == 0 {
+        &[]
+    } else {
+        &ZEROS[rest..]
+    }
+}
+
+async fn send(tx: &Sender<Result<Bytes, ThreadSafeError>>, data: Bytes) -> Result<()> {
+    if tx.send(Ok(data)).await.is_err() {
+        bail!("client went away");
+    }
+    Ok(())
+}
+
+async fn send_file(
+    tx: &Sender<Result<Bytes, ThreadSafeError>>,
+    name: &str,
+    contents: Vec<u8>,
+) -> Result<()> {
+    let size = contents.len() as u64;
+    send(tx, Bytes::copy_from_slice(&tar_header(name, size)?)).await?;
+    send(tx, Bytes::from(contents)).await?;
+    send(tx, Bytes::from_static(tar_padding(size))).await
+}
+
+/// Queries the closure of `store_path` with a narinfo for each path, signed with `secret_keys`.
+fn query_archive_paths(store_path: &str, secret_keys: Vec<String>) -> Result<Vec<ArchivePath>> {
+    let closure = query_closure(store_path, Radix::default())?;
+    let mut paths = Vec::with_capacity(closure.len());
+    for (path, info) in closure {
+        let hash = hash_part(&path).with_context(|| format!("Invalid store path {path}"))?;
+        let mut narinfo = query_narinfo(&path, hash, &secret_keys)
+            .map_err(|e| anyhow::anyhow!("Couldn't query narinfo of {path}: {e}"))?;
+        narinfo.url = format!("nar/{hash}.nar");
+        paths.push(ArchivePath {
+            hash: hash.to_owned(),
+            narinfo: format_narinfo_txt(&narinfo),
+            nar_hash: info.narhash,
+            nar_size: info.size,
+            references: info.refs,
+            store_path: path,
+        });
+    }
+    Ok(paths)
+}
+
+/// Streams the closure as a tar archive laid out like a file:// binary cache, so it can be
+/// imported with `nix copy --from file://<dir>` after unpacking.
+async fn write_closure(
+    settings: web::Data<Config>,
+    store_path: String,
+    secret_keys: Vec<String>,
+    tx: &Sender<Result<Bytes, ThreadSafeError>>,
+) -> Result<()> {
+    let paths = {
+        let store_path = store_path.clone();
+        web::block(move || query_archive_paths(&store_path, secret_keys)).await??
+    };
+    write_archive(
+        store_path,
+        libnixstore::get_store_dir(),
+        &paths,
+        |path| settings.store.get_real_path(path),
+        tx,
+    )
+    .await
+}
+
+/// Writes the archive of `paths`, the closure of `root`. The nars are dumped from the file
+/// system paths returned by `real_path`.
+async fn write_archive(
+    root: String,
+    store_dir: String,
+    paths: &[ArchivePath],
+    real_path: impl Fn(&str) -> PathBuf,
+    tx: &Sender<Result<Bytes, ThreadSafeError>>,
+) -> Result<()> {
+    send_file(
+        tx,
+        "nix-cache-info",
+        format!("StoreDir: {store_dir}\n").into_bytes(),
+    )
+    .await?;
+    let manifest = Manifest {
+        root,
+        store_dir,
+        paths: paths
+            .iter()
+            .map(|p| ManifestEntry {
+                store_path: p.store_path.clone(),
+                nar: format!("nar/{}.nar", p.hash),
+                nar_hash: p.nar_hash.clone(),
+                nar_size: p.nar_size,
+                references: p.references.clone(),
+            })
+            .collect(),
+    };
+    send_file(tx, "manifest.json", serde_json::to_vec_pretty(&manifest)?).await?;
+    for p in paths {
+        let name = format!("{}.narinfo", p.hash);
+        send_file(tx, &name, p.narinfo.as_bytes().to_vec()).await?;
+    }
+
+    for p in paths {
+        let (path, size) = (&p.store_path, p.nar_size);
+        let name = format!("nar/{}.nar", p.hash);
+        send(tx, Bytes::copy_from_slice(&tar_header(&name, size)?)).await?;
+
+        let (nar_tx, mut nar_rx) = mpsc::channel(1000);
+        let real_path = real_path(path);
+        let dumper = task::spawn(async move { dump_path(real_path, &nar_tx).await });
+        let mut sent = 0;
+        while let Some(data) = nar_rx.recv().await {
+            let Ok(data) = data else {
+                break;
+            };
+            sent += data.len() as u64;
+            send(tx, data).await?;
+        }
+        dumper
+            .await?
+            .with_context(|| format!("Error dumping path {path}"))?;
+        // the size is already in the tar header, a different one would corrupt the archive
+        if sent != size {
+            bail!("Nar of {path} has {sent} bytes, but {size} are registered");
+        }
+        send(tx, Bytes::from_static(tar_padding(size))).await?;
+    }
+
+    // end of archive
+    send(tx, Bytes::from_static(&[0; 2 * TAR_BLOCK_SIZE])).await
+}
+
+pub(crate) async fn get(
+    hash: NixHash32,
+    req: HttpRequest,
+    settings: web::Data<Config>,
+) -> Result<HttpResponse, Box<dyn Error>> {
+    let store_path = some_or_404!(nixhash(hash.as_str()));
+    let secret_keys = settings.for_request(&req).secret_keys.clone();
+    let (tx, rx) = mpsc::channel::<Result<Bytes, ThreadSafeError>>(16);
+    let level = settings
+        .compression
+        .zstd_level
+        .unwrap_or(zstd::DEFAULT_COMPRESSION_LEVEL);
+    let compressor = settings.compressor.clone();
+    let filename = format!("{}.tar.zst", hash.as_str());
+    task::spawn(async move {
+        let path = store_path.clone();
+        if let Err(err) = write_closure(settings, store_path, secret_keys, &tx).await {
+            log::error!("Error sending closure of {}: {:?}", path, err);
+            let _ = tx.send(Err(ThreadSafeError)).await;
+        }
+    });
+
+    Ok(HttpResponse::Ok()
+        .insert_header((http::header::CONTENT_TYPE, "application/zstd"))
+        .insert_header(http::header::ContentDisposition::attachment(filename))
+        .insert_header(cache_control_max_age_1y())
+        .streaming(compressor.compress(Encoding::Zstd(level), rx)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_tar_header_is_deterministic() {
+        let a = tar_header("nar/abc.nar", 1234).unwrap();
+        let b = tar_header("nar/abc.nar", 1234).unwrap();
+        assert_eq!(a, b);
+        let header = tar::Header::from_byte_slice(&a);
+        assert_eq!(header.size().unwrap(), 1234);
+        assert_eq!(header.mtime().unwrap(), 0);
+        assert_eq!(
+            &*header.path().unwrap(),
+            std::path::Path::new("nar/abc.nar")
+        );
+    }
+
+    #[test]
+    fn test_tar_padding() {
+        assert_eq!(tar_padding(0).len(), 0);
+        assert_eq!(tar_padding(1).len(), 511);
+        assert_eq!(tar_padding(512).len(), 0);
+        assert_eq!(tar_padding(1000).len(), 24);
+    }
+
+    async fn collect(mut rx: mpsc::Receiver<Result<Bytes, ThreadSafeError>>) -> Vec<u8> {
+        let mut data = Vec::new();
+        while let Some(chunk) = rx.recv().await {
+            data.extend_from_slice(&chunk.expect("stream failed"));
+        }
+        data
+    }
+
+    async fn dump(path: PathBuf) -> Vec<u8> {
+        let (tx, rx) = mpsc::channel(1000);
+        let dumper = task::spawn(async move { dump_path(path, &tx).await });
+        let nar = collect(rx).await;
+        dumper.await.unwrap().unwrap();
+        nar
+    }
+
+    #[tokio::test]
+    async fn test_write_archive() {
+        let dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let store = dir.path().join("store");
+        let lib = "0k1j8wmg1m7kdyhcl7aywjkjy7gbjzbs-lib";
+        let app = "1k1j8wmg1m7kdyhcl7aywjkjy7gbjzbs-app";
+        std::fs::create_dir_all(store.join(lib)).unwrap();
+        std::fs::write(store.join(lib).join("libfoo.so"), "lib").unwrap();
+        std::fs::write(store.join(app), "#!/bin/sh\n").unwrap();
+
+        let mut paths = Vec::new();
+        let mut nars = BTreeMap::new();
+        for (name, refs) in [(app, vec![lib]), (lib, vec![])] {
+            let nar = dump(store.join(name)).await;
+            let hash = hash_part(name).unwrap().to_owned();
+            paths.push(ArchivePath {
+                store_path: format!("/nix/store/{name}"),
+                narinfo: format!("StorePath: /nix/store/{name}\nURL: nar/{hash}.nar\n"),
+                nar_hash: "sha256:fixture".to_owned(),
+                nar_size: nar.len() as u64,
+                references: refs.iter().map(|r| format!("/nix/store/{r}")).collect(),
+                hash: hash.clone(),
+            });
+            nars.insert(hash, nar);
+        }
+
+        let (tx, rx) = mpsc::channel(16);
+        let writer = {
+            let store = store.clone();
+            task::spawn(async move {
+                write_archive(
+                    format!("/nix/store/{app}"),
+                    "/nix/store".to_owned(),
+                    &paths,
+                    |path| store.join(path.strip_prefix("/nix/store/").unwrap()),
+                    &tx,
+                )
+                .await
+            })
+        };
+        let archive = collect(rx).await;
+        writer.await.unwrap().unwrap();
+
+        let mut names = Vec::new();
+        let mut entries = BTreeMap::new();
+        for entry in tar::Archive::new(archive.as_slice()).entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let name = entry.path().unwrap().to_string_lossy().into_owned();
+            let mut contents = Vec::new();
+            std::io::Read::read_to_end(&mut entry, &mut contents).unwrap();
+            names.push(name.clone());
+            entries.insert(name, contents);
+        }
+        let (app_hash, lib_hash) = (&app[..32], &lib[..32]);
+        assert_eq!(
+            names,
+            [
+                "nix-cache-info".to_owned(),
+                "manifest.json".to_owned(),
+                format!("{app_hash}.narinfo"),
+                format!("{lib_hash}.narinfo"),
+                format!("nar/{app_hash}.nar"),
+                format!("nar/{lib_hash}.nar"),
+            ]
+        );
+        assert_eq!(entries["nix-cache-info"], b"StoreDir: /nix/store\n");
+        for (hash, nar) in &nars {
+            assert_eq!(&entries[&format!("nar/{hash}.nar")], nar);
+            let narinfo = String::from_utf8(entries[&format!("{hash}.narinfo")].clone()).unwrap();
+            assert!(narinfo.contains(&format!("URL: nar/{hash}.nar\n")));
+        }
+        let manifest: serde_json::Value =
+            serde_json::from_slice(&entries["manifest.json"]).unwrap();
+        assert_eq!(manifest["root"], format!("/nix/store/{app}"));
+        assert_eq!(manifest["paths"].as_array().unwrap().len(), 2);
+        assert_eq!(
+            manifest["paths"][0]["references"][0],
+            format!("/nix/store/{lib}")
+        );
+    }
+}
@@ -0,0 +1,302 @@
+use std::io::{self, Write};
+use std::sync::Arc;
+
+use actix_web::web::Bytes;
+use anyhow::{Context, Result};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task;
+use tokio_stream::wrappers::ReceiverStream;
+
+use crate::config::CompressionConfig;
+
+/// Input is collected into chunks of this size before it is handed to the pool, the NAR dumper
+/// produces many tiny writes for headers.
+const CHUNK_SIZE: usize = 256 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Encoding {
+    Zstd(i32),
+    Gzip(u32),
+}
+
+impl Encoding {
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Encoding::Zstd(_) => "zstd",
+            Encoding::Gzip(_) => "gzip",
+        }
+    }
+}
+
+enum Encoder {
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+    Gzip(flate2::write::GzEncoder<Vec<u8>>),
+}
+
+impl Encoder {
+    fn new(encoding: Encoding) -> io::Result<Self> {
+        Ok(match encoding {
+            Encoding::Zstd(level) => {
+                Encoder::Zstd(zstd::stream::write::Encoder::new(Vec::new(), level)?)
+            }
+            Encoding::Gzip(level) => Encoder::Gzip(flate2::write::GzEncoder::new(
+                Vec::new(),
+                flate2::Compression::new(level),
+            )),
+        })
+    }
+
+    /// Compresses `data` and returns the output produced so far.
+    fn compress(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        Ok(match self {
+            Encoder::Zstd(e) => {
+                e.write_all(data)?;
+                std::mem::take(e.get_mut())
+            }
+            Encoder::Gzip(e) => {
+                e.write_all(data)?;
+                std::mem::take(e.get_mut())
+            }
+        })
+    }
+
+    fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Encoder::Zstd(e) => e.finish(),
+            Encoder::Gzip(e) => e.finish(),
+        }
+    }
+}
+
+// We send this error across thread boundaries, so it must be Send + Sync
+#[derive(Debug)]
+pub(crate) struct CompressionError;
+impl std::error::Error for CompressionError {}
+impl std::fmt::Display for CompressionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "compression failed")
+    }
+}
+
+/// Compresses response streams on a thread pool that is shared by all workers, so a few large
+/// downloads don't each occupy the single thread of a worker runtime.
+#[derive(Debug, Default)]
+pub(crate) struct Compressor {
+    pool: Option<rayon::ThreadPool>,
+    zstd_level: Option<i32>,
+    gzip_level: Option<u32>,
+}
+
+/// Returns whether a client accepts `encoding` according to its Accept-Encoding header.
+pub(crate) fn accepts(accept_encoding: &str, encoding: &str) -> bool {
+    quality(accept_encoding, encoding).is_some_and(|q| q > 0.0)
+}
+
+/// Returns the quality value a client assigned to `encoding` in an Accept-Encoding header.
+/// Codings are compared case-insensitively and `*` applies to codings not listed otherwise, as
+/// RFC 9110 section 12.5.3 requires. Items with an invalid quality value are ignored.
+fn quality(accept_encoding: &str, encoding: &str) -> Option<f32> {
+    let mut wildcard = None;
+    for item in accept_encoding.split(',') {
+        let mut parts = item.split(';').map(str::trim);
+        let Some(coding) = parts.next() else {
+            continue;
+        };
+        let q = parts
+            .filter_map(|p| p.split_once('='))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("q"))
+            .map_or(Some(1.0), |(_, q)| q.trim().parse::<f32>().ok());
+        if coding.eq_ignore_ascii_case(encoding) {
+            return q;
+        }
+        if coding == "*" {
+            wildcard = wildcard.or(q);
+        }
+    }
+    wildcard
+}
+
+impl Compressor {
+    pub(crate) fn new(config: &CompressionConfig) -> Result<Self> {
+        if config.zstd_level.is_none() && config.gzip_level.is_none() {
+            return Ok(Self::default());
+        }
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(config.threads)
+            .thread_name(|i| format!("compression-{i}"))
+            .build()
+            .context("Couldn't start compression threads")?;
+        Ok(Self {
+            pool: Some(pool),
+            zstd_level: config.zstd_level,
+            gzip_level: config.gzip_level,
+        })
+    }
+
+    pub(crate) fn enabled(&self) -> bool {
+        self.pool.is_some()
+    }
+
+    /// Picks the encoding for a response based on the request's Accept-Encoding header.
+    /// zstd is preferred over gzip when the client accepts both equally.
+    pub(crate) fn negotiate(&self, accept_encoding: Option<&str>) -> Option<Encoding> {
+        if !self.enabled() {
+            return None;
+        }
+        let accept_encoding = accept_encoding?;
+        let zstd = self
+            .zstd_level
+            .and_then(|l| Some((quality(accept_encoding, "zstd")?, Encoding::Zstd(l))));
+        let gzip = self
+            .gzip_level
+            .and_then(|l| Some((quality(accept_encoding, "gzip")?, Encoding::Gzip(l))));
+        [zstd, gzip]
+            .into_iter()
+            .flatten()
+            .filter(|(q, _)| *q > 0.0)
+            .fold(
+                None,
+                |best: Option<(f32, Encoding)>, candidate| match best {
+                    Some(best) if best.0 >= candidate.0 => Some(best),
+                    _ => Some(candidate),
+                },
+            )
+            .map(|(_, encoding)| encoding)
+    }
+
+    async fn run<T: Send + 'static>(&self, f: impl FnOnce() -> T + Send + 'static) -> Result<T> {
+        // endpoints that always compress still work without a pool
+        let Some(pool) = &self.pool else {
+            return task::spawn_blocking(f)
+                .await
+                .context("compression thread went away");
+        };
+        let (tx, rx) = oneshot::channel();
+        pool.spawn(move || {
+            let _ = tx.send(f());
+        });
+        rx.await.context("compression thread went away")
+    }
+
+    async fn compress_stream<E: Send + 'static>(
+        &self,
+        encoding: Encoding,
+        mut rx: mpsc::Receiver<Result<Bytes, E>>,
+        tx: &mpsc::Sender<Result<Bytes, CompressionError>>,
+    ) -> Result<()> {
+        let mut encoder = Encoder::new(encoding).context("Failed to create encoder")?;
+        loop {
+            let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+            let mut eof = false;
+            while chunk.len() < CHUNK_SIZE {
+                match rx.recv().await {
+                    Some(Ok(data)) => chunk.extend_from_slice(&data),
+                    // the producer already logged why it failed
+                    Some(Err(_)) => anyhow::bail!("Input stream failed"),
+                    None => {
+                        eof = true;
+                        break;
+                    }
+                }
+            }
+            let (e, output) = self
+                .run(move || {
+                    let output = encoder.compress(&chunk);
+                    (encoder, output)
+                })
+                .await?;
+            let mut output = output.context("Failed to compress")?;
+            if eof {
+                let rest = self.run(move || e.finish()).await?;
+                output.extend_from_slice(&rest.context("Failed to finish compression")?);
+                let _ = tx.send(Ok(Bytes::from(output))).await;
+                return Ok(());
+            }
+            encoder = e;
+            if !output.is_empty() && tx.send(Ok(Bytes::from(output))).await.is_err() {
+                // client went away
+                return Ok(());
+            }
+        }
+    }
+
+    /// Returns a stream with the compressed contents of `rx`.
+    pub(crate) fn compress<E: Send + 'static>(
+        self: Arc<Self>,
+        encoding: Encoding,
+        rx: mpsc::Receiver<Result<Bytes, E>>,
+    ) -> ReceiverStream<Result<Bytes, CompressionError>> {
+        let (tx, compressed) = mpsc::channel(16);
+        task::spawn(async move {
+            if let Err(err) = self.compress_stream(encoding, rx, &tx).await {
+                log::error!("Error compressing response: {:?}", err);
+                let _ = tx.send(Err(CompressionError)).await;
+            }
+        });
+        ReceiverStream::new(compressed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn compressor(zstd_level: Option<i32>, gzip_level: Option<u32>) -> Compressor {
+        Compressor::new(&CompressionConfig {
+            threads: 1,
+            zstd_level,
+            gzip_level,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_accepts() {
+        assert!(accepts("gzip, deflate, br", "gzip"));
+        assert!(accepts("zstd;q=0.5", "zstd"));
+        assert!(!accepts("gzip;q=0", "gzip"));
+        assert!(!accepts("zstd;q=invalid", "zstd"));
+        assert!(!accepts("gzip", "zstd"));
+        assert!(!accepts("", "gzip"));
+        assert!(accepts("GZip", "gzip"));
+        assert!(accepts("zstd;Q=0.5", "zstd"));
+        assert!(accepts("*", "zstd"));
+        assert!(!accepts("*;q=0", "zstd"));
+        assert!(!accepts("zstd;q=0, *", "zstd"));
+        assert!(accepts("*;q=0, zstd", "zstd"));
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let c = compressor(Some(3), Some(6));
+        assert_eq!(c.negotiate(None), None);
+        assert_eq!(c.negotiate(Some("")), None);
+        assert_eq!(c.negotiate(Some("br, identity")), None);
+        assert_eq!(c.negotiate(Some("gzip")), Some(Encoding::Gzip(6)));
+        assert_eq!(c.negotiate(Some("gzip, zstd")), Some(Encoding::Zstd(3)));
+        assert_eq!(
+            c.negotiate(Some("zstd;q=0.5, gzip")),
+            Some(Encoding::Gzip(6))
+        );
+        assert_eq!(
+            c.negotiate(Some("zstd; q=0.8 , gzip;q=0.2")),
+            Some(Encoding::Zstd(3))
+        );
+        assert_eq!(c.negotiate(Some("zstd;q=0, gzip;q=0")), None);
+        assert_eq!(c.negotiate(Some("GZIP")), Some(Encoding::Gzip(6)));
+        assert_eq!(c.negotiate(Some("*")), Some(Encoding::Zstd(3)));
+        assert_eq!(c.negotiate(Some("gzip, *;q=0.5")), Some(Encoding::Gzip(6)));
+        assert_eq!(c.negotiate(Some("zstd;q=0, *")), Some(Encoding::Gzip(6)));
+        assert_eq!(c.negotiate(Some("identity, *;q=0")), None);
+    }
+
+    #[test]
+    fn test_negotiate_configured_encodings() {
+        assert_eq!(compressor(None, None).negotiate(Some("zstd, gzip")), None);
+        assert_eq!(
+            compressor(None, Some(6)).negotiate(Some("zstd, gzip;q=0.1")),
+            Some(Encoding::Gzip(6))
+        );
+        assert_eq!(compressor(Some(3), None).negotiate(Some("gzip")), None);
+    }
+}
@@ -1,9 +1,16 @@
+use std::collections::BTreeMap;
 use std::fs::read_to_string;
+use std::net::ToSocketAddrs;
+use std::sync::Arc;
 
+use crate::compression::Compressor;
+use crate::headers::ResponseHeaders;
+use crate::stats::Stats;
 use crate::store::Store;
-use anyhow::{Context, Result};
+use actix_web::{http, HttpRequest};
+use anyhow::{bail, Context, Result};
 use base64::{engine::general_purpose, Engine};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 fn default_bind() -> String {
     "[::]:5000".into()
@@ -21,8 +28,217 @@ fn default_priority() -> usize {
     30
 }
 
+fn default_want_mass_query() -> bool {
+    true
+}
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub(crate) struct CacheInfoConfig {
+    #[serde(default)]
+    pub(crate) compression: Vec<String>,
+    #[serde(default)]
+    pub(crate) extra: BTreeMap<String, String>,
+}
+
+fn default_secure_headers() -> bool {
+    true
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub(crate) struct HeadersConfig {
+    #[serde(default = "default_secure_headers")]
+    pub(crate) secure_defaults: bool,
+    #[serde(default)]
+    pub(crate) all: BTreeMap<String, String>,
+    #[serde(default)]
+    pub(crate) html: BTreeMap<String, String>,
+}
+
+impl Default for HeadersConfig {
+    fn default() -> Self {
+        Self {
+            secure_defaults: default_secure_headers(),
+            all: BTreeMap::new(),
+            html: BTreeMap::new(),
+        }
+    }
+}
+
+fn default_mmap_threshold() -> u64 {
+    64 * 1024
+}
+
+fn default_inline_types() -> Vec<String> {
+    [
+        "text/*",
+        "image/*",
+        "audio/*",
+        "video/*",
+        "application/pdf",
+        "application/json",
+        "application/javascript",
+        "application/wasm",
+    ]
+    .map(String::from)
+    .to_vec()
+}
+
+fn default_follow_cross_store_symlinks() -> bool {
+    true
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub(crate) struct ServeConfig {
+    #[serde(default = "default_mmap_threshold")]
+    pub(crate) mmap_threshold: u64,
+    #[serde(default)]
+    pub(crate) mime_types: BTreeMap<String, String>,
+    #[serde(default = "default_inline_types")]
+    pub(crate) inline_types: Vec<String>,
+    #[serde(default = "default_follow_cross_store_symlinks")]
+    pub(crate) follow_cross_store_symlinks: bool,
+}
+
+impl Default for ServeConfig {
+    fn default() -> Self {
+        Self {
+            mmap_threshold: default_mmap_threshold(),
+            mime_types: BTreeMap::new(),
+            inline_types: default_inline_types(),
+            follow_cross_store_symlinks: default_follow_cross_store_symlinks(),
+        }
+    }
+}
+
+fn default_compression_threads() -> usize {
+    std::thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub(crate) struct CompressionConfig {
+    #[serde(default = "default_compression_threads")]
+    pub(crate) threads: usize,
+    #[serde(default)]
+    pub(crate) zstd_level: Option<i32>,
+    #[serde(default)]
+    pub(crate) gzip_level: Option<u32>,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            threads: default_compression_threads(),
+            zstd_level: None,
+            gzip_level: None,
+        }
+    }
+}
+
+fn default_health_timeout_ms() -> u64 {
+    5000
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub(crate) struct HealthConfig {
+    /// A store path that is queried by deep health checks.
+    #[serde(default)]
+    pub(crate) check_path: Option<String>,
+    #[serde(default = "default_health_timeout_ms")]
+    pub(crate) timeout_ms: u64,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            check_path: None,
+            timeout_ms: default_health_timeout_ms(),
+        }
+    }
+}
+
+fn default_stats_max_paths() -> usize {
+    10000
+}
+
+fn default_stats_flush_interval() -> u64 {
+    300
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub(crate) struct StatsConfig {
+    /// SQLite database per store path counters are flushed to.
+    #[serde(default)]
+    pub(crate) database: Option<String>,
+    #[serde(default = "default_stats_flush_interval")]
+    pub(crate) flush_interval: u64,
+    #[serde(default = "default_stats_max_paths")]
+    pub(crate) max_paths: usize,
+    /// Show which store paths clients request without the admin token.
+    #[serde(default)]
+    pub(crate) public: bool,
+}
+
+impl Default for StatsConfig {
+    fn default() -> Self {
+        Self {
+            database: None,
+            flush_interval: default_stats_flush_interval(),
+            max_paths: default_stats_max_paths(),
+            public: false,
+        }
+    }
+}
+
+fn default_nar_delta_level() -> i32 {
+    3
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub(crate) struct NarDeltaConfig {
+    /// Deltas are disabled unless this is set, as both nars are kept in memory.
+    #[serde(default)]
+    pub(crate) max_nar_size: Option<u64>,
+    #[serde(default = "default_nar_delta_level")]
+    pub(crate) level: i32,
+}
+
+impl Default for NarDeltaConfig {
+    fn default() -> Self {
+        Self {
+            max_nar_size: None,
+            level: default_nar_delta_level(),
+        }
+    }
+}
+
+/// Overrides for requests whose `Host` matches `host`. Unset fields fall back to the global
+/// configuration.
+#[derive(Deserialize, Serialize, Debug)]
+pub(crate) struct VirtualHostConfig {
+    pub(crate) host: String,
+    #[serde(default)]
+    pub(crate) priority: Option<usize>,
+    #[serde(default)]
+    pub(crate) want_mass_query: Option<bool>,
+    #[serde(default)]
+    pub(crate) cache_info: Option<CacheInfoConfig>,
+    #[serde(default)]
+    pub(crate) sign_key_paths: Option<Vec<String>>,
+
+    #[serde(skip, default)]
+    pub(crate) secret_keys: Option<Vec<String>>,
+}
+
+/// The settings that apply to a single request, after virtual host overrides.
+pub(crate) struct HostSettings<'a> {
+    pub(crate) priority: usize,
+    pub(crate) want_mass_query: bool,
+    pub(crate) cache_info: &'a CacheInfoConfig,
+    pub(crate) secret_keys: &'a Vec<String>,
+}
+
 // TODO(conni2461): users to restrict access
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
 pub(crate) struct Config {
     #[serde(default = "default_bind")]
     pub(crate) bind: String,
@@ -32,15 +248,99 @@ pub(crate) struct Config {
     pub(crate) max_connection_rate: usize,
     #[serde(default = "default_priority")]
     pub(crate) priority: usize,
+    #[serde(default = "default_want_mass_query")]
+    pub(crate) want_mass_query: bool,
+    #[serde(default)]
+    pub(crate) cache_info: CacheInfoConfig,
     #[serde(default)]
     pub(crate) sign_key_path: Option<String>,
     #[serde(default)]
     pub(crate) sign_key_paths: Vec<String>,
+    #[serde(default)]
+    pub(crate) verify_nar_hash: bool,
+    #[serde(default)]
+    pub(crate) headers: HeadersConfig,
+    #[serde(default)]
+    pub(crate) admin_token_path: Option<String>,
+    #[serde(default)]
+    pub(crate) gc_roots_dir: Option<String>,
+    #[serde(default)]
+    pub(crate) serve: ServeConfig,
+    #[serde(default)]
+    pub(crate) compression: CompressionConfig,
+    #[serde(default)]
+    pub(crate) health: HealthConfig,
+    #[serde(default, rename = "stats")]
+    pub(crate) stats_config: StatsConfig,
+    #[serde(default)]
+    pub(crate) nar_delta: NarDeltaConfig,
+    #[serde(default, rename = "vhost")]
+    pub(crate) vhosts: Vec<VirtualHostConfig>,
 
     #[serde(skip, default)]
     pub(crate) secret_keys: Vec<String>,
     #[serde(skip)]
+    pub(crate) response_headers: ResponseHeaders,
+    #[serde(skip, default)]
+    pub(crate) admin_token: Option<String>,
+    #[serde(skip)]
+    pub(crate) compressor: Arc<Compressor>,
+    #[serde(skip)]
     pub(crate) store: Store,
+    #[serde(skip)]
+    pub(crate) stats: Stats,
+}
+
+impl Config {
+    /// Returns the settings for the host `req` was sent to, see [`request_host`].
+    pub(crate) fn for_request(&self, req: &HttpRequest) -> HostSettings<'_> {
+        self.for_host(request_host(req))
+    }
+
+    /// Returns the settings for a request to `host`, as given by the `Host` header. The port is
+    /// ignored and names are compared case-insensitively.
+    fn for_host(&self, host: &str) -> HostSettings<'_> {
+        let vhost = strip_port(host).and_then(|name| {
+            self.vhosts
+                .iter()
+                .find(|v| v.host.eq_ignore_ascii_case(name))
+        });
+        HostSettings {
+            priority: vhost.and_then(|v| v.priority).unwrap_or(self.priority),
+            want_mass_query: vhost
+                .and_then(|v| v.want_mass_query)
+                .unwrap_or(self.want_mass_query),
+            cache_info: vhost
+                .and_then(|v| v.cache_info.as_ref())
+                .unwrap_or(&self.cache_info),
+            secret_keys: vhost
+                .and_then(|v| v.secret_keys.as_ref())
+                .unwrap_or(&self.secret_keys),
+        }
+    }
+}
+
+/// Returns the host `req` was sent to, from the `Host` header or the authority of the URI for
+/// HTTP/2. Unlike `connection_info()` this ignores `Forwarded` and `X-Forwarded-Host`, which any
+/// client can set to pick the signing keys of another virtual host.
+fn request_host(req: &HttpRequest) -> &str {
+    req.headers()
+        .get(http::header::HOST)
+        .and_then(|h| h.to_str().ok())
+        .or_else(|| req.uri().authority().map(|a| a.as_str()))
+        .unwrap_or_default()
+}
+
+/// Returns the host name without the port, or `None` if `host` is empty.
+fn strip_port(host: &str) -> Option<&str> {
+    let name = if let Some(rest) = host.strip_prefix('[') {
+        // ipv6 literal
+        rest.split_once(']').map_or(rest, |(addr, _)| addr)
+    } else {
+        host.rsplit_once(':').map_or(host, |(name, _)| name)
+    };
+    let name = name.trim_end_matches('.');
+    (!name.is_empty()).then_some(name)
 }
 
 fn get_secret_key(sign_key_path: Option<&str>) -> Result<Option<String>> {
@@ -61,18 +361,148 @@ fn get_secret_key(sign_key_path: Option<&str>) -> Result<Option<String>> {
     Ok(None)
 }
 
-pub(crate) fn load() -> Result<Config> {
+fn get_admin_token(path: &str) -> Result<String> {
+    let token = read_to_string(path)
+        .with_context(|| format!("Couldn't read admin token file '{path}'"))?
+        .trim()
+        .to_owned();
+    if token.is_empty() {
+        bail!("Admin token file '{path}' is empty");
+    }
+    Ok(token)
+}
+
+fn check_cache_info(cache_info: &CacheInfoConfig) -> Result<()> {
+    for algo in &cache_info.compression {
+        if algo.is_empty() || algo.contains(char::is_whitespace) {
+            bail!("Invalid compression '{algo}' in [cache_info]");
+        }
+    }
+    for (key, value) in &cache_info.extra {
+        if key.is_empty() || key.contains(|c: char| c == ':' || c.is_whitespace()) {
+            bail!("Invalid field name '{key}' in [cache_info.extra]");
+        }
+        if value.contains('\n') {
+            bail!("Value of '{key}' in [cache_info.extra] must not contain newlines");
+        }
+    }
+    Ok(())
+}
+
+const ENV_PREFIX: &str = "HARMONIA_";
+
+/// Top-level fields of [`Config`] that can be overridden from the environment.
+const CONFIG_FIELDS: &[&str] = &[
+    "bind",
+    "workers",
+    "max_connection_rate",
+    "priority",
+    "want_mass_query",
+    "cache_info",
+    "sign_key_path",
+    "sign_key_paths",
+    "verify_nar_hash",
+    "headers",
+    "admin_token_path",
+    "gc_roots_dir",
+    "serve",
+    "compression",
+    "health",
+    "stats",
+    "nar_delta",
+    "vhost",
+];
+
+/// Overrides config fields with `HARMONIA_<FIELD>` environment variables. Fields in sections are
+/// separated by a double underscore, e.g. `HARMONIA_COMPRESSION__ZSTD_LEVEL`. Values are parsed
+/// as TOML, falling back to a plain string, so `HARMONIA_SIGN_KEY_PATHS='["/a", "/b"]'` and
+/// `HARMONIA_BIND=[::]:5000` both work. Variables that don't name a config field, e.g. because
+/// of a typo, are an error with `strict` and skipped with a warning otherwise.
+fn apply_env_overrides(
+    table: &mut toml::Table,
+    vars: impl IntoIterator<Item = (String, String)>,
+    strict: bool,
+) -> Result<()> {
+    for (name, value) in vars {
+        let Some(key) = name.strip_prefix(ENV_PREFIX) else {
+            continue;
+        };
+        let path = key
+            .split("__")
+            .map(|part| part.to_ascii_lowercase())
+            .collect::<Vec<_>>();
+        if path.iter().any(|part| part.is_empty()) {
+            bail!("Invalid config override '{name}'");
+        }
+        if !CONFIG_FIELDS.contains(&path[0].as_str()) {
+            if strict {
+                bail!("Unknown config field '{}' in '{name}'", path[0]);
+            }
+            log::warn!("Ignoring {name}, '{}' is not a config field", path[0]);
+            continue;
+        }
+        let value = toml::from_str::<toml::Table>(&format!("value = {value}"))
+            .ok()
+            .and_then(|mut t| t.remove("value"))
+            .unwrap_or(toml::Value::String(value));
+
+        let (field, sections) = path.split_last().expect("split returns at least one part");
+        let mut current = &mut *table;
+        for section in sections {
+            current = current
+                .entry(section.clone())
+                .or_insert_with(|| toml::Value::Table(toml::Table::new()))
+                .as_table_mut()
+                .with_context(|| format!("'{section}' is not a section, set by '{name}'"))?;
+        }
+        log::info!("Overriding config field '{}' from {name}", path.join("."));
+        current.insert(field.clone(), value);
+    }
+    Ok(())
+}
+
+/// Checks the parts of a loaded config that are only used once the server starts and returns
+/// the effective config including defaults. Secrets are never part of the output.
+pub(crate) fn check(settings: &Config) -> Result<String> {
+    let addrs = settings
+        .bind
+        .to_socket_addrs()
+        .with_context(|| format!("Couldn't resolve bind address '{}'", settings.bind))?;
+    if addrs.count() == 0 {
+        bail!("Bind address '{}' resolves to nothing", settings.bind);
+    }
+    toml::to_string_pretty(settings).context("Couldn't serialize config")
+}
+
+/// Reads the config file, applies overrides and validates the result. Apart from reading the
+/// files the config refers to, like signing keys, this has no side effects, so it backs
+/// `--check-config`, which sets `strict` to reject unknown `HARMONIA_*` variables. Use [`setup`]
+/// to make the config ready for serving.
+pub(crate) fn parse(strict: bool) -> Result<Config> {
     let settings_file = std::env::var("CONFIG_FILE").unwrap_or_else(|_| "settings.toml".to_owned());
-    let mut settings: Config = toml::from_str(
-        &read_to_string(&settings_file)
-            .with_context(|| format!("Couldn't read config file '{settings_file}'"))?,
-    )
-    .with_context(|| format!("Couldn't parse config file '{settings_file}'"))?;
-    if let Some(sign_key_path) = &settings.sign_key_path {
+    let contents = read_to_string(&settings_file)
+        .with_context(|| format!("Couldn't read config file '{settings_file}'"))?;
+    let overrides = std::env::vars()
+        .filter(|(name, _)| name.starts_with(ENV_PREFIX))
+        .collect::<Vec<_>>();
+    let mut settings: Config = if overrides.is_empty() {
+        // parse directly, so errors point to the line in the file
+        toml::from_str(&contents)
+            .with_context(|| format!("Couldn't parse config file '{settings_file}'"))?
+    } else {
+        let mut table: toml::Table = toml::from_str(&contents)
+            .with_context(|| format!("Couldn't parse config file '{settings_file}'"))?;
+        apply_env_overrides(&mut table, overrides, strict)?;
+        toml::Value::Table(table).try_into().with_context(|| {
+            format!("Invalid configuration in '{settings_file}' with HARMONIA_* overrides")
+        })?
+    };
+    // merged into sign_key_paths, so `check` doesn't print the key twice
+    if let Some(sign_key_path) = settings.sign_key_path.take() {
         log::warn!(
             "The sign_key_path configuration option is deprecated. Use sign_key_paths instead."
         );
-        settings.sign_key_paths.push(sign_key_path.to_string());
+        settings.sign_key_paths.push(sign_key_path);
     }
     if let Ok(sign_key_path) = std::env::var("SIGN_KEY_PATH") {
         log::warn!(
@@ -90,6 +520,224 @@ pub(crate) fn load() -> Result<Config> {
             settings.secret_keys.push(sk);
         }
     }
+    if let Some(admin_token_path) = &settings.admin_token_path {
+        settings.admin_token = Some(get_admin_token(admin_token_path)?);
+    }
+    if settings.gc_roots_dir.is_some() && settings.admin_token.is_none() {
+        bail!("gc_roots_dir requires admin_token_path to be set");
+    }
+    check_cache_info(&settings.cache_info)
+        .with_context(|| format!("Invalid [cache_info] section in '{settings_file}'"))?;
+    for (i, vhost) in settings.vhosts.iter_mut().enumerate() {
+        let host = vhost.host.trim_end_matches('.').to_owned();
+        if strip_port(&host) != Some(host.as_str()) {
+            bail!("Invalid host '{}' in [[vhost]] entry {i}", vhost.host);
+        }
+        vhost.host.clone_from(&host);
+        if let Some(cache_info) = &vhost.cache_info {
+            check_cache_info(cache_info)
+                .with_context(|| format!("Invalid cache_info for [[vhost]] '{host}'"))?;
+        }
+        if let Some(sign_key_paths) = &vhost.sign_key_paths {
+            let mut secret_keys = Vec::with_capacity(sign_key_paths.len());
+            for sign_key_path in sign_key_paths {
+                if let Some(sk) = get_secret_key(Some(sign_key_path))? {
+                    secret_keys.push(sk);
+                }
+            }
+            vhost.secret_keys = Some(secret_keys);
+        }
+    }
+    for (i, vhost) in settings.vhosts.iter().enumerate() {
+        if settings.vhosts[..i]
+            .iter()
+            .any(|v| v.host.eq_ignore_ascii_case(&vhost.host))
+        {
+            bail!("Duplicate [[vhost]] entry for '{}'", vhost.host);
+        }
+    }
+    for (extension, mime_type) in &settings.serve.mime_types {
+        mime_type.parse::<mime::Mime>().with_context(|| {
+            format!(
+                "Invalid mime type '{mime_type}' for extension '{extension}' in [serve.mime_types]"
+            )
+        })?;
+    }
+    if let Some(level) = settings.compression.zstd_level {
+        if !zstd::compression_level_range().contains(&level) {
+            bail!("Invalid zstd_level {level} in [compression]");
+        }
+    }
+    if let Some(level) = settings.compression.gzip_level {
+        if level > 9 {
+            bail!("Invalid gzip_level {level} in [compression], must be between 0 and 9");
+        }
+    }
+    if !zstd::compression_level_range().contains(&settings.nar_delta.level) {
+        bail!("Invalid level {} in [nar_delta]", settings.nar_delta.level);
+    }
+    for pattern in &settings.serve.inline_types {
+        let valid = match pattern.split_once('/') {
+            Some((type_, "*")) => !type_.is_empty(),
+            _ => pattern.parse::<mime::Mime>().is_ok(),
+        };
+        if !valid {
+            bail!("Invalid mime type pattern '{pattern}' in [serve.inline_types]");
+        }
+    }
+    settings.response_headers = ResponseHeaders::new(&settings.headers)
+        .with_context(|| format!("Invalid [headers] section in '{settings_file}'"))?;
+    if settings.stats_config.max_paths == 0 {
+        bail!("max_paths in [stats] must be greater than 0");
+    }
+    if settings.stats_config.flush_interval == 0 {
+        bail!("flush_interval in [stats] must be greater than 0");
+    }
+    Ok(settings)
+}
+
+/// Creates what a parsed config needs at runtime: the gc roots directory, the compression
+/// threads, the stats database and the store.
+pub(crate) fn setup(mut settings: Config) -> Result<Config> {
+    if let Some(gc_roots_dir) = &settings.gc_roots_dir {
+        std::fs::create_dir_all(gc_roots_dir)
+            .with_context(|| format!("Couldn't create gc_roots_dir '{gc_roots_dir}'"))?;
+    }
+    settings.compressor = Arc::new(Compressor::new(&settings.compression)?);
+    settings.stats = Stats::new(
+        settings.stats_config.max_paths,
+        settings.stats_config.database.as_deref(),
+    )?;
     settings.store = Store::new();
     Ok(settings)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_env_overrides() -> Result<()> {
+        let mut table: toml::Table = toml::from_str("priority = 30\n[compression]\nthreads = 2\n")?;
+        apply_env_overrides(
+            &mut table,
+            vars(&[
+                ("HARMONIA_BIND", "[::]:8080"),
+                ("HARMONIA_PRIORITY", "40"),
+                ("HARMONIA_SIGN_KEY_PATHS", r#"["/a", "/b"]"#),
+                ("HARMONIA_COMPRESSION__ZSTD_LEVEL", "3"),
+                ("HARMONIA_SERVE__MIME_TYPES__NIX", "text/plain"),
+                ("OTHER_BIND", "ignored"),
+            ]),
+            true,
+        )?;
+        let config: Config = toml::Value::Table(table).try_into()?;
+        assert_eq!(config.bind, "[::]:8080");
+        assert_eq!(config.priority, 40);
+        assert_eq!(config.sign_key_paths, ["/a", "/b"]);
+        assert_eq!(config.compression.threads, 2);
+        assert_eq!(config.compression.zstd_level, Some(3));
+        assert_eq!(config.serve.mime_types["nix"], "text/plain");
+        Ok(())
+    }
+
+    #[test]
+    fn test_env_overrides_invalid() {
+        let mut table: toml::Table = toml::from_str("bind = \"[::]:5000\"\n").unwrap();
+        assert!(
+            apply_env_overrides(&mut table, vars(&[("HARMONIA_BIND__X", "1")]), false).is_err()
+        );
+        assert!(apply_env_overrides(&mut table, vars(&[("HARMONIA___X", "1")]), false).is_err());
+    }
+
+    #[test]
+    fn test_env_overrides_unknown_field() -> Result<()> {
+        let typo = vars(&[("HARMONIA_SIGN_KEY_PAHT", "/a")]);
+        let mut table = toml::Table::new();
+        assert!(apply_env_overrides(&mut table, typo.clone(), true).is_err());
+        apply_env_overrides(&mut table, typo, false)?;
+        assert!(table.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_fields() -> Result<()> {
+        let config: Config = toml::from_str(
+            "sign_key_path = \"/a\"\nadmin_token_path = \"/b\"\ngc_roots_dir = \"/c\"\n",
+        )?;
+        let table = toml::Table::try_from(&config)?;
+        let mut fields = table.keys().map(String::as_str).collect::<Vec<_>>();
+        fields.sort_unstable();
+        let mut expected = CONFIG_FIELDS.to_vec();
+        expected.sort_unstable();
+        assert_eq!(fields, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_for_host() -> Result<()> {
+        let mut config: Config = toml::from_str(
+            "priority = 30\n[[vhost]]\nhost = \"cache.example.org\"\npriority = 50\n",
+        )?;
+        config.secret_keys.push("global".into());
+        assert_eq!(config.for_host("cache.example.org").priority, 50);
+        assert_eq!(config.for_host("Cache.Example.Org:5000").priority, 50);
+        assert_eq!(config.for_host("cache.example.org.").priority, 50);
+        assert_eq!(config.for_host("other.example.org").priority, 30);
+        assert_eq!(config.for_host("").priority, 30);
+        assert_eq!(
+            config.for_host("cache.example.org").secret_keys,
+            &["global"]
+        );
+
+        config.vhosts[0].secret_keys = Some(vec![]);
+        assert!(config.for_host("cache.example.org").secret_keys.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_for_request_ignores_forwarded_host() -> Result<()> {
+        use actix_web::test::TestRequest;
+
+        let mut config: Config = toml::from_str("[[vhost]]\nhost = \"cache.example.org\"\n")?;
+        config.secret_keys.push("global".into());
+        config.vhosts[0].secret_keys = Some(vec!["vhost".into()]);
+
+        let req = TestRequest::default()
+            .insert_header((http::header::HOST, "cache.example.org"))
+            .to_http_request();
+        assert_eq!(config.for_request(&req).secret_keys, &["vhost"]);
+
+        let spoofed = TestRequest::default()
+            .insert_header((http::header::HOST, "other.example.org"))
+            .insert_header(("X-Forwarded-Host", "cache.example.org"))
+            .insert_header((http::header::FORWARDED, "host=cache.example.org"))
+            .to_http_request();
+        assert_eq!(config.for_request(&spoofed).secret_keys, &["global"]);
+
+        let spoofed = TestRequest::default()
+            .insert_header((http::header::HOST, "cache.example.org"))
+            .insert_header(("X-Forwarded-Host", "other.example.org"))
+            .to_http_request();
+        assert_eq!(config.for_request(&spoofed).secret_keys, &["vhost"]);
+
+        // HTTP/2 requests carry the host in the uri instead
+        let req =
+            TestRequest::with_uri("https://cache.example.org/nix-cache-info").to_http_request();
+        assert_eq!(config.for_request(&req).secret_keys, &["vhost"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_strip_port() {
+        assert_eq!(strip_port("example.org:443"), Some("example.org"));
+        assert_eq!(strip_port("[::1]:5000"), Some("::1"));
+        assert_eq!(strip_port(":5000"), None);
+    }
+}
@@ -0,0 +1,234 @@
+use std::fmt;
+use std::future::{ready, Ready};
+
+use actix_web::error::InternalError;
+use actix_web::{dev, http, FromRequest, HttpRequest, HttpResponse};
+
+use crate::{cache_control_max_age_1d, cache_control_no_store, NIXBASE32_ALPHABET};
+
+/// Length of the hash part of a store path.
+pub(crate) const HASH_PART_LEN: usize = 32;
+
+/// Length of a sha256 nar hash in nixbase32.
+pub(crate) const NAR_HASH_LEN: usize = 52;
+
+fn is_nixbase32(c: char) -> bool {
+    NIXBASE32_ALPHABET.contains(c)
+}
+
+/// Returns the hash part of `path`, which is either a store path, its base name or a hash part
+/// itself. Returns `None` if the name doesn't start with a hash part followed by `-` or nothing.
+pub(crate) fn hash_part(path: &str) -> Option<&str> {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    let hash = name.get(..HASH_PART_LEN)?;
+    let rest = &name[HASH_PART_LEN..];
+    (hash.chars().all(is_nixbase32) && (rest.is_empty() || rest.starts_with('-'))).then_some(hash)
+}
+
+/// A nixbase32 hash taken from a request URL.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum UrlHash {
+    /// The hash was requested in its canonical form.
+    Canonical(String),
+    /// The hash was requested in a different spelling; clients should be redirected to the URL
+    /// with the canonical one.
+    Normalized(String),
+}
+
+impl UrlHash {
+    pub(crate) fn into_inner(self) -> String {
+        match self {
+            UrlHash::Canonical(hash) | UrlHash::Normalized(hash) => hash,
+        }
+    }
+}
+
+/// Parses a nixbase32 hash of `len` characters. Uppercase letters are accepted, as is trailing
+/// garbage that can't be part of the hash, like the name of a store path (`<hash>-hello-2.12`),
+/// which some clients send. Returns `None` if `s` doesn't start with such a hash.
+pub(crate) fn parse(s: &str, len: usize) -> Option<UrlHash> {
+    if s.len() == len && s.chars().all(is_nixbase32) {
+        return Some(UrlHash::Canonical(s.to_owned()));
+    }
+    let hash = s.get(..len)?.to_ascii_lowercase();
+    // a hash character right after the hash would make it longer than `len`
+    let rest = &s[len..];
+    if !hash.chars().all(is_nixbase32)
+        || rest.starts_with(|c: char| is_nixbase32(c.to_ascii_lowercase()))
+    {
+        return None;
+    }
+    Some(UrlHash::Normalized(hash))
+}
+
+/// Permanently redirects to `location`, keeping the query string of `req`.
+fn redirect(req: &HttpRequest, location: String) -> HttpResponse {
+    let location = match req.query_string() {
+        "" => location,
+        query => format!("{location}?{query}"),
+    };
+    HttpResponse::MovedPermanently()
+        .insert_header((http::header::LOCATION, location))
+        .insert_header(cache_control_max_age_1d())
+        .finish()
+}
+
+fn bad_request(msg: String) -> actix_web::Error {
+    let res = HttpResponse::BadRequest()
+        .insert_header(cache_control_no_store())
+        .body(msg.clone());
+    InternalError::from_response(msg, res).into()
+}
+
+/// Parses the route segment `name` as a hash of `len` characters. Returns `None` if the route
+/// has no such segment, a redirect to the canonical URL if the hash isn't in canonical form and
+/// a 400 response if it isn't a hash.
+fn extract(req: &HttpRequest, name: &str, len: usize) -> Result<Option<String>, actix_web::Error> {
+    let Some(raw) = req.match_info().get(name) else {
+        return Ok(None);
+    };
+    match parse(raw, len) {
+        Some(UrlHash::Canonical(hash)) => Ok(Some(hash)),
+        Some(UrlHash::Normalized(hash)) => {
+            // the segment is percent-decoded, so it can only be replaced if it is spelled
+            // the same in the url. Otherwise the normalized hash is used as is.
+            match req.path().find(raw) {
+                Some(start) => {
+                    let path = req.path();
+                    let location =
+                        format!("{}{hash}{}", &path[..start], &path[start + raw.len()..]);
+                    Err(InternalError::from_response(
+                        format!("non-canonical hash '{raw}'"),
+                        redirect(req, location),
+                    )
+                    .into())
+                }
+                None => Ok(Some(hash)),
+            }
+        }
+        None => Err(bad_request(format!(
+            "invalid {name} '{raw}': expected {len} nixbase32 characters"
+        ))),
+    }
+}
+
+fn extract_required(req: &HttpRequest, name: &str, len: usize) -> Result<String, actix_web::Error> {
+    extract(req, name, len)?.ok_or_else(|| {
+        actix_web::error::ErrorInternalServerError(format!("route has no {name} segment"))
+    })
+}
+
+/// The hash part of a store path, taken from the `{hash}` route segment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct NixHash32(String);
+
+impl NixHash32 {
+    /// Extracts the hash part from the route segment `name`, or `None` if the route has no such
+    /// segment.
+    pub(crate) fn from_segment(
+        req: &HttpRequest,
+        name: &str,
+    ) -> Result<Option<Self>, actix_web::Error> {
+        Ok(extract(req, name, HASH_PART_LEN)?.map(Self))
+    }
+
+    /// Like [`NixHash32::from_segment`], for routes that always have the segment `name`.
+    pub(crate) fn from_required_segment(
+        req: &HttpRequest,
+        name: &str,
+    ) -> Result<Self, actix_web::Error> {
+        extract_required(req, name, HASH_PART_LEN).map(Self)
+    }
+
+    pub(crate) fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for NixHash32 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromRequest for NixHash32 {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut dev::Payload) -> Self::Future {
+        ready(extract_required(req, "hash", HASH_PART_LEN).map(Self))
+    }
+}
+
+/// A sha256 nar hash in nixbase32, taken from the `{narhash}` route segment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct NarHash52(String);
+
+impl fmt::Display for NarHash52 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromRequest for NarHash52 {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut dev::Payload) -> Self::Future {
+        ready(extract_required(req, "narhash", NAR_HASH_LEN).map(Self))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const HASH: &str = "0k1j8wmg1m7kdyhcl7aywjkjy7gbjzbs";
+
+    #[test]
+    fn test_hash_part() {
+        assert_eq!(hash_part(HASH), Some(HASH));
+        assert_eq!(hash_part(&format!("{HASH}-hello-2.12")), Some(HASH));
+        assert_eq!(
+            hash_part(&format!("/nix/store/{HASH}-hello-2.12")),
+            Some(HASH)
+        );
+        assert_eq!(hash_part(""), None);
+        assert_eq!(hash_part("/nix/store/"), None);
+        assert_eq!(hash_part(&HASH[1..]), None);
+        assert_eq!(hash_part(&format!("{HASH}a")), None);
+        assert_eq!(hash_part(&format!("{HASH}.narinfo")), None);
+        assert_eq!(hash_part(&HASH.to_uppercase()), None);
+        assert_eq!(hash_part("ü"), None);
+    }
+
+    #[test]
+    fn test_parse_canonical() {
+        assert_eq!(
+            parse(HASH, HASH_PART_LEN),
+            Some(UrlHash::Canonical(HASH.to_owned()))
+        );
+    }
+
+    #[test]
+    fn test_parse_normalized() {
+        let normalized = Some(UrlHash::Normalized(HASH.to_owned()));
+        assert_eq!(parse(&HASH.to_uppercase(), HASH_PART_LEN), normalized);
+        assert_eq!(
+            parse(&format!("{HASH}-hello-2.12"), HASH_PART_LEN),
+            normalized
+        );
+        assert_eq!(parse(&format!("{HASH} "), HASH_PART_LEN), normalized);
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert_eq!(parse("", HASH_PART_LEN), None);
+        assert_eq!(parse(&HASH[1..], HASH_PART_LEN), None);
+        assert_eq!(parse(&format!("{HASH}a"), HASH_PART_LEN), None);
+        assert_eq!(parse(&format!("{HASH}A"), HASH_PART_LEN), None);
+        // 'e' is not part of the nixbase32 alphabet
+        assert_eq!(parse(&format!("e{}", &HASH[1..]), HASH_PART_LEN), None);
+        assert_eq!(parse(&format!("E{}", &HASH[1..]), HASH_PART_LEN), None);
+    }
+}
@@ -0,0 +1,183 @@
+use std::collections::BTreeMap;
+
+use actix_web::dev::ServiceResponse;
+use actix_web::http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use actix_web::HttpResponse;
+use anyhow::{Context, Result};
+
+use crate::config::HeadersConfig;
+
+/// Headers added to every response when `secure_defaults` is enabled.
+const SECURE_DEFAULTS: &[(&str, &str)] = &[("x-content-type-options", "nosniff")];
+
+/// Headers added to the HTML pages rendered by harmonia itself when `secure_defaults` is enabled.
+/// The policy allows the bootstrap assets referenced by `BOOTSTRAP_SOURCE` and nothing else.
+const SECURE_HTML_DEFAULTS: &[(&str, &str)] = &[
+    (
+        "content-security-policy",
+        "default-src 'none'; style-src https://cdn.jsdelivr.net; script-src https://cdn.jsdelivr.net; img-src 'self' data:; base-uri 'none'; form-action 'none'; frame-ancestors 'none'",
+    ),
+    ("x-frame-options", "DENY"),
+    ("referrer-policy", "no-referrer"),
+];
+
+/// Marks a response as a file from the store. Store contents may be whole websites that bring
+/// their own assets, so the HTML headers are not applied to them.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct StoreContent;
+
+/// Marks `res` as [`StoreContent`].
+pub(crate) fn store_content<B>(mut res: HttpResponse<B>) -> HttpResponse<B> {
+    res.extensions_mut().insert(StoreContent);
+    res
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct ResponseHeaders {
+    all: HeaderMap,
+    html: HeaderMap,
+}
+
+fn build_header_map(
+    section: &str,
+    defaults: &[(&'static str, &'static str)],
+    configured: &BTreeMap<String, String>,
+) -> Result<HeaderMap> {
+    let mut headers = HeaderMap::new();
+    for (name, value) in defaults {
+        headers.insert(
+            HeaderName::from_static(name),
+            HeaderValue::from_static(value),
+        );
+    }
+    for (name, value) in configured {
+        let name = HeaderName::try_from(name.as_str())
+            .with_context(|| format!("Invalid header name '{name}' in [{section}]"))?;
+        let value = HeaderValue::try_from(value.as_str())
+            .with_context(|| format!("Invalid value for header '{name}' in [{section}]"))?;
+        headers.insert(name, value);
+    }
+    Ok(headers)
+}
+
+fn is_generated_html<B>(res: &ServiceResponse<B>) -> bool {
+    let is_html = res
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with(mime::TEXT_HTML.essence_str()));
+    is_html && res.response().extensions().get::<StoreContent>().is_none()
+}
+
+impl ResponseHeaders {
+    pub(crate) fn new(config: &HeadersConfig) -> Result<Self> {
+        let (defaults, html_defaults) = if config.secure_defaults {
+            (SECURE_DEFAULTS, SECURE_HTML_DEFAULTS)
+        } else {
+            (&[][..], &[][..])
+        };
+        Ok(Self {
+            all: build_header_map("headers.all", defaults, &config.all)?,
+            html: build_header_map("headers.html", html_defaults, &config.html)?,
+        })
+    }
+
+    /// Adds the configured headers to `res`, replacing any value set by the handler.
+    pub(crate) fn apply<B>(&self, res: &mut ServiceResponse<B>) {
+        let html = is_generated_html(res);
+        let headers = res.headers_mut();
+        for (name, value) in self.all.iter() {
+            headers.insert(name.clone(), value.clone());
+        }
+        if html {
+            for (name, value) in self.html.iter() {
+                headers.insert(name.clone(), value.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn headers(secure_defaults: bool) -> ResponseHeaders {
+        ResponseHeaders::new(&HeadersConfig {
+            secure_defaults,
+            all: BTreeMap::from([("x-all".to_owned(), "1".to_owned())]),
+            html: BTreeMap::from([("x-html".to_owned(), "1".to_owned())]),
+        })
+        .unwrap()
+    }
+
+    fn apply(headers: &ResponseHeaders, path: &str, res: HttpResponse) -> HeaderMap {
+        let mut res = TestRequest::with_uri(path).to_srv_response(res);
+        headers.apply(&mut res);
+        res.headers().clone()
+    }
+
+    fn html() -> HttpResponse {
+        HttpResponse::Ok()
+            .content_type(mime::TEXT_HTML_UTF_8)
+            .finish()
+    }
+
+    #[test]
+    fn test_generated_html() {
+        let applied = apply(&headers(true), "/", html());
+        assert!(applied.contains_key("x-all"));
+        assert!(applied.contains_key("x-html"));
+        assert!(applied.contains_key("content-security-policy"));
+        assert!(applied.contains_key("x-frame-options"));
+        assert!(applied.contains_key("x-content-type-options"));
+    }
+
+    #[test]
+    fn test_directory_listing() {
+        let applied = apply(&headers(true), "/serve/hash/dir/", html());
+        assert!(applied.contains_key("x-html"));
+        assert!(applied.contains_key("content-security-policy"));
+    }
+
+    #[test]
+    fn test_store_content() {
+        let applied = apply(
+            &headers(true),
+            "/serve/hash/index.html",
+            store_content(html()),
+        );
+        assert!(applied.contains_key("x-all"));
+        assert!(applied.contains_key("x-content-type-options"));
+        assert!(!applied.contains_key("x-html"));
+        assert!(!applied.contains_key("content-security-policy"));
+    }
+
+    #[test]
+    fn test_not_html() {
+        let res = HttpResponse::Ok()
+            .content_type(mime::APPLICATION_JSON)
+            .finish();
+        let applied = apply(&headers(true), "/api/v1/stats", res);
+        assert!(applied.contains_key("x-all"));
+        assert!(!applied.contains_key("x-html"));
+        assert!(!applied.contains_key("content-security-policy"));
+    }
+
+    #[test]
+    fn test_without_secure_defaults() {
+        let applied = apply(&headers(false), "/", html());
+        assert!(applied.contains_key("x-html"));
+        assert!(!applied.contains_key("x-content-type-options"));
+        assert!(!applied.contains_key("content-security-policy"));
+    }
+
+    #[test]
+    fn test_configured_replaces_handler() {
+        let res = HttpResponse::Ok()
+            .insert_header(("x-all", "handler"))
+            .finish();
+        let applied = apply(&headers(false), "/", res);
+        assert_eq!(applied.get("x-all").unwrap(), "1");
+    }
+}
@@ -1,7 +1,403 @@
+use std::collections::BTreeMap;
 use std::error::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
-use actix_web::HttpResponse;
+use actix_web::{web, HttpResponse};
+use libnixstore::Radix;
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
 
-pub(crate) async fn get() -> Result<HttpResponse, Box<dyn Error>> {
-    Ok(HttpResponse::Ok().body("OK\n"))
+use crate::cache_control_no_store;
+use crate::config::Config;
+use crate::hash::hash_part;
+
+/// Hash part that is never used by a real store path, used to exercise lookups when no
+/// `check_path` is configured.
+const PROBE_HASH: &str = "00000000000000000000000000000000";
+
+static STORE_CHECK: Check = Check::new();
+static DATABASE_CHECK: Check = Check::new();
+static SIGNING_KEYS_CHECK: Check = Check::new();
+
+#[derive(Debug, Deserialize)]
+pub struct Param {
+    #[serde(default, deserialize_with = "crate::deserialize_flag")]
+    deep: bool,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct CheckResult {
+    ok: bool,
+    duration_ms: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct DeepHealth {
+    status: &'static str,
+    checks: BTreeMap<&'static str, CheckResult>,
+}
+
+/// State of a check shared by all probes, so that overlapping probes share a single run.
+struct Check {
+    /// Whether the blocking code of a run hasn't returned yet, which outlives the run if it
+    /// timed out.
+    running: AtomicBool,
+    /// Result of the run in flight, for probes arriving while it is running.
+    pending: Mutex<Option<watch::Receiver<Option<CheckResult>>>>,
+}
+
+impl Check {
+    const fn new() -> Self {
+        Self {
+            running: AtomicBool::new(false),
+            pending: Mutex::new(None),
+        }
+    }
+}
+
+/// Clears the running flag of a check once its blocking code has returned.
+struct Running(&'static AtomicBool);
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+/// Clears the run in flight of a check once it is over, including when the probe that started
+/// it goes away.
+struct Pending(&'static Check);
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        *self.0.pending.lock().unwrap_or_else(|e| e.into_inner()) = None;
+    }
+}
+
+/// Runs a blocking check on the thread pool, failing it if it takes longer than `timeout`.
+/// Probes arriving while a run is in flight wait for it and share its result. A timed out check
+/// keeps running, as blocking code can't be cancelled, so until it returns the check fails right
+/// away. Otherwise every probe against a hung daemon would take up another thread of the pool.
+async fn run_check<F>(state: &'static Check, timeout: Duration, check: F) -> CheckResult
+where
+    F: FnOnce() -> Result<(), String> + Send + 'static,
+{
+    let started = Instant::now();
+    let tx = loop {
+        let mut rx = {
+            let mut pending = state.pending.lock().unwrap_or_else(|e| e.into_inner());
+            match &*pending {
+                Some(rx) => rx.clone(),
+                None if state.running.load(Ordering::Acquire) => {
+                    return CheckResult {
+                        ok: false,
+                        duration_ms: 0,
+                        error: Some("previous check is still running".to_owned()),
+                    };
+                }
+                None => {
+                    let (tx, rx) = watch::channel(None);
+                    *pending = Some(rx);
+                    state.running.store(true, Ordering::Release);
+                    break tx;
+                }
+            }
+        };
+        // an error means the probe running the check went away before it was done
+        let res = rx.wait_for(Option::is_some).await.map(|res| res.clone());
+        if let Ok(Some(res)) = res {
+            return res;
+        }
+    };
+    let pending = Pending(state);
+    let running = Running(&state.running);
+    let check = move || {
+        let _running = running;
+        check()
+    };
+    let res = match tokio::time::timeout(timeout, web::block(check)).await {
+        Ok(Ok(res)) => res,
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err(format!("timed out after {} ms", timeout.as_millis())),
+    };
+    let res = CheckResult {
+        ok: res.is_ok(),
+        duration_ms: started.elapsed().as_millis(),
+        error: res.err(),
+    };
+    drop(pending);
+    tx.send_replace(Some(res.clone()));
+    res
+}
+
+/// Round-trip to the store, e.g. the nix daemon. Fails if the store returns an error, and with a
+/// `check_path` also if that path isn't valid.
+fn check_store(check_path: Option<String>) -> Result<(), String> {
+    match check_path {
+        Some(path) => libnixstore::query_path_info(&path, Radix::default())
+            .map(|_| ())
+            .map_err(|e| format!("cannot query {path}: {e}")),
+        None => {
+            let probe = format!("{}/{PROBE_HASH}-health", libnixstore::get_store_dir());
+            libnixstore::try_is_valid_path(&probe)
+                .map(|_| ())
+                .map_err(|e| format!("cannot query the store: {e}"))
+        }
+    }
+}
+
+/// Lookup in the hash part index of the database of valid paths, which is a different query
+/// than the one of `check_store`.
+fn check_database(check_path: Option<String>) -> Result<(), String> {
+    let hash = match &check_path {
+        Some(path) => hash_part(path).ok_or_else(|| format!("{path} is not a store path"))?,
+        None => PROBE_HASH,
+    };
+    let found = libnixstore::try_query_path_from_hash_part(hash)
+        .map_err(|e| format!("cannot look up {hash}: {e}"))?;
+    match (&check_path, found) {
+        (None, _) => Ok(()),
+        (Some(path), Some(found)) if found == *path => Ok(()),
+        (Some(path), Some(found)) => Err(format!("{hash} resolved to {found} instead of {path}")),
+        (Some(path), None) => Err(format!("{path} not found in the database")),
+    }
+}
+
+fn check_signing_keys(secret_keys: Vec<String>) -> Result<(), String> {
+    for (i, key) in secret_keys.iter().enumerate() {
+        libnixstore::sign_string(key, "harmonia-health-check")
+            .map_err(|e| format!("signing with key {i} failed: {e}"))?;
+    }
+    Ok(())
+}
+
+pub(crate) async fn get(
+    param: web::Query<Param>,
+    settings: web::Data<Config>,
+) -> Result<HttpResponse, Box<dyn Error>> {
+    if !param.deep {
+        return Ok(HttpResponse::Ok().body("OK\n"));
+    }
+
+    let timeout = Duration::from_millis(settings.health.timeout_ms);
+    let check_path = settings.health.check_path.clone();
+    let secret_keys = settings
+        .vhosts
+        .iter()
+        .filter_map(|v| v.secret_keys.as_ref())
+        .fold(settings.secret_keys.clone(), |mut keys, v| {
+            keys.extend(v.iter().cloned());
+            keys
+        });
+    let (store, database, signing_keys) = tokio::join!(
+        run_check(&STORE_CHECK, timeout, {
+            let check_path = check_path.clone();
+            move || check_store(check_path)
+        }),
+        run_check(&DATABASE_CHECK, timeout, move || check_database(check_path)),
+        run_check(&SIGNING_KEYS_CHECK, timeout, move || {
+            check_signing_keys(secret_keys)
+        }),
+    );
+
+    let mut checks = BTreeMap::new();
+    checks.insert("store", store);
+    checks.insert("database", database);
+    checks.insert("signing_keys", signing_keys);
+    Ok(deep_health(checks))
+}
+
+/// Renders the results of the deep checks, with a 503 status if any of them failed.
+fn deep_health(checks: BTreeMap<&'static str, CheckResult>) -> HttpResponse {
+    let healthy = checks.values().all(|c| c.ok);
+    let body = DeepHealth {
+        status: if healthy { "ok" } else { "degraded" },
+        checks,
+    };
+    let mut res = if healthy {
+        HttpResponse::Ok()
+    } else {
+        HttpResponse::ServiceUnavailable()
+    };
+    res.insert_header(cache_control_no_store()).json(body)
+}
+
+/// Liveness probe: the process is up and serving requests.
+pub(crate) async fn live() -> Result<HttpResponse, Box<dyn Error>> {
+    Ok(HttpResponse::Ok()
+        .insert_header(cache_control_no_store())
+        .body("OK\n"))
+}
+
+/// Readiness probe: the store and its database can be queried, so requests can actually be
+/// answered. Runs of the checks are shared with overlapping deep health and readiness probes.
+pub(crate) async fn ready(settings: web::Data<Config>) -> Result<HttpResponse, Box<dyn Error>> {
+    let timeout = Duration::from_millis(settings.health.timeout_ms);
+    let check_path = settings.health.check_path.clone();
+    let (store, database) = tokio::join!(
+        run_check(&STORE_CHECK, timeout, {
+            let check_path = check_path.clone();
+            move || check_store(check_path)
+        }),
+        run_check(&DATABASE_CHECK, timeout, move || check_database(check_path)),
+    );
+    Ok(readiness([store, database]))
+}
+
+fn readiness(checks: impl IntoIterator<Item = CheckResult>) -> HttpResponse {
+    if let Some(error) = checks.into_iter().find_map(|c| c.error) {
+        return HttpResponse::ServiceUnavailable()
+            .insert_header(cache_control_no_store())
+            .body(format!("NOT READY: {error}\n"));
+    }
+    HttpResponse::Ok()
+        .insert_header(cache_control_no_store())
+        .body("OK\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::http;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    async fn passing() -> CheckResult {
+        static CHECK: Check = Check::new();
+        run_check(&CHECK, TIMEOUT, || Ok(())).await
+    }
+
+    async fn failing_store() -> CheckResult {
+        static CHECK: Check = Check::new();
+        run_check(&CHECK, TIMEOUT, || {
+            Err("cannot connect to the daemon".to_owned())
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_deep_health_ok() {
+        let checks = BTreeMap::from([("store", passing().await), ("database", passing().await)]);
+        assert_eq!(deep_health(checks).status(), http::StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_deep_health_failing_store() {
+        let store = failing_store().await;
+        assert!(!store.ok);
+        assert_eq!(store.error.as_deref(), Some("cannot connect to the daemon"));
+        let checks = BTreeMap::from([("store", store), ("database", passing().await)]);
+        assert_eq!(
+            deep_health(checks).status(),
+            http::StatusCode::SERVICE_UNAVAILABLE
+        );
+    }
+
+    #[tokio::test]
+    async fn test_readiness() {
+        assert_eq!(
+            readiness([passing().await, passing().await]).status(),
+            http::StatusCode::OK
+        );
+        assert_eq!(
+            readiness([failing_store().await, passing().await]).status(),
+            http::StatusCode::SERVICE_UNAVAILABLE
+        );
+    }
+
+    #[tokio::test]
+    async fn test_check_timeout() {
+        static CHECK: Check = Check::new();
+        let (tx, rx) = std::sync::mpsc::channel::<()>();
+        let hung = run_check(&CHECK, Duration::from_millis(10), move || {
+            let _ = rx.recv();
+            Ok(())
+        })
+        .await;
+        assert!(!hung.ok);
+        assert_eq!(hung.error.as_deref(), Some("timed out after 10 ms"));
+
+        // the hung check still occupies a thread, so it isn't started again
+        let started = std::sync::Arc::new(AtomicBool::new(false));
+        let skipped = run_check(&CHECK, TIMEOUT, {
+            let started = started.clone();
+            move || {
+                started.store(true, Ordering::SeqCst);
+                Ok(())
+            }
+        })
+        .await;
+        assert!(!skipped.ok);
+        assert_eq!(
+            skipped.error.as_deref(),
+            Some("previous check is still running")
+        );
+        assert!(!started.load(Ordering::SeqCst));
+
+        // once it returns, checks run again
+        tx.send(()).unwrap();
+        while CHECK.running.load(Ordering::Acquire) {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        assert!(run_check(&CHECK, TIMEOUT, || Ok(())).await.ok);
+    }
+
+    #[tokio::test]
+    async fn test_overlapping_checks() {
+        static CHECK: Check = Check::new();
+        let (tx, rx) = std::sync::mpsc::channel::<()>();
+        let first = run_check(&CHECK, TIMEOUT, move || {
+            let _ = rx.recv();
+            Ok(())
+        });
+        let started = std::sync::Arc::new(AtomicBool::new(false));
+        let second = async {
+            // probe again while the first run is still in flight
+            while !CHECK.running.load(Ordering::Acquire) {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+            let second = run_check(&CHECK, TIMEOUT, {
+                let started = started.clone();
+                move || {
+                    started.store(true, Ordering::SeqCst);
+                    Ok(())
+                }
+            });
+            tokio::pin!(second);
+            assert!(tokio::time::timeout(Duration::from_millis(10), &mut second)
+                .await
+                .is_err());
+            tx.send(()).unwrap();
+            second.await
+        };
+        let (first, second) = tokio::join!(first, second);
+        assert!(first.ok);
+        assert!(second.ok);
+        // the second probe shared the result of the first run
+        assert!(!started.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_overlapping_readiness() {
+        static STORE: Check = Check::new();
+        static DATABASE: Check = Check::new();
+        async fn probe() -> HttpResponse {
+            let slow = || {
+                std::thread::sleep(Duration::from_millis(20));
+                Ok(())
+            };
+            let (store, database) = tokio::join!(
+                run_check(&STORE, TIMEOUT, slow),
+                run_check(&DATABASE, TIMEOUT, slow),
+            );
+            readiness([store, database])
+        }
+        let (first, second) = tokio::join!(probe(), probe());
+        assert_eq!(first.status(), http::StatusCode::OK);
+        assert_eq!(second.status(), http::StatusCode::OK);
+    }
 }
@@ -1,26 +1,59 @@
-use std::{fmt::Display, time::Duration};
+use std::{
+    fmt::Display,
+    time::{Duration, Instant},
+};
 
-use actix_web::{http, web, App, HttpResponse, HttpServer};
+use actix_web::{
+    body::{BodySize, MessageBody},
+    dev::Service,
+    http, web, App, HttpResponse, HttpServer,
+};
 
+mod admin;
+mod api;
 mod buildlog;
+mod bulk;
 mod cacheinfo;
+mod closure;
+mod compression;
 mod config;
+mod hash;
+mod headers;
 mod health;
 mod nar;
+mod nardelta;
 mod narinfo;
 mod narlist;
 mod root;
 mod serve;
+mod stats;
 mod store;
 mod version;
 
 fn nixhash(hash: &str) -> Option<String> {
-    if hash.len() != 32 {
+    if hash.len() != hash::HASH_PART_LEN {
         return None;
     }
     libnixstore::query_path_from_hash_part(hash)
 }
 
+/// Deserializes a query flag like `?closure=1`: `1` and `true` enable it, `0` and `false` disable
+/// it and anything else is rejected. Use with `#[serde(default)]` so a missing flag is disabled.
+fn deserialize_flag<'de, D>(deserializer: D) -> Result<bool, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let value = <String as serde::Deserialize>::deserialize(deserializer)?;
+    match value.as_str() {
+        "1" | "true" => Ok(true),
+        "0" | "false" => Ok(false),
+        _ => Err(serde::de::Error::invalid_value(
+            serde::de::Unexpected::Str(&value),
+            &"1, true, 0 or false",
+        )),
+    }
+}
+
 const BOOTSTRAP_SOURCE: &str = r#"
   <link href="https://cdn.jsdelivr.net/npm/bootstrap@5.2.3/dist/css/bootstrap.min.css"
         rel="stylesheet"
@@ -96,8 +129,19 @@ async fn main() -> std::io::Result<()> {
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
     libnixstore::init();
 
-    let c = match config::load() {
-        Ok(v) => web::Data::new(v),
+    let check_config = std::env::args().skip(1).any(|arg| arg == "--check-config");
+    let c = match config::parse(check_config).and_then(|c| {
+        if check_config {
+            config::check(&c).map(|effective| {
+                print!("{effective}");
+                None
+            })
+        } else {
+            config::setup(c).map(Some)
+        }
+    }) {
+        Ok(Some(v)) => web::Data::new(v),
+        Ok(None) => return Ok(()),
         Err(e) => {
             log::error!("{e}");
             e.chain()
@@ -108,17 +152,64 @@ async fn main() -> std::io::Result<()> {
     };
     let config_data = c.clone();
 
+    if c.stats_config.database.is_some() {
+        let stats_config = c.clone();
+        actix_web::rt::spawn(async move {
+            let mut interval = actix_web::rt::time::interval(Duration::from_secs(
+                stats_config.stats_config.flush_interval,
+            ));
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = stats_config.stats.flush_requested() => {}
+                }
+                let config = stats_config.clone();
+                match web::block(move || config.stats.flush()).await {
+                    Ok(Err(e)) => log::error!("Failed to flush access statistics: {e:?}"),
+                    Err(e) => log::error!("Failed to flush access statistics: {e}"),
+                    Ok(Ok(())) => {}
+                }
+            }
+        });
+    }
+
     log::info!("listening on {}", c.bind);
-    HttpServer::new(move || {
+    let res = HttpServer::new(move || {
+        let headers_config = config_data.clone();
         App::new()
             .app_data(config_data.clone())
+            .wrap_fn(move |req, srv| {
+                let started = Instant::now();
+                let method = req.method().clone();
+                let path = req.path().to_owned();
+                let query = req.query_string().to_owned();
+                let res = srv.call(req);
+                let config = headers_config.clone();
+                async move {
+                    let mut res = res.await?;
+                    config.response_headers.apply(&mut res);
+                    config.stats.record(&method, &path, res.status(), started);
+                    if res.status().is_success() {
+                        if let Some(hash) = stats::store_hash(&path, &query) {
+                            let bytes = match res.response().body().size() {
+                                BodySize::Sized(n) => n,
+                                _ => 0,
+                            };
+                            config.stats.record_path(hash, bytes);
+                        }
+                    }
+                    Ok(res)
+                }
+            })
             .route("/", web::get().to(root::get))
             .route("/{hash}.ls", web::get().to(narlist::get))
             .route("/{hash}.ls", web::head().to(narlist::get))
             .route("/{hash}.narinfo", web::get().to(narinfo::get))
             .route("/{hash}.narinfo", web::head().to(narinfo::get))
             .route(
-                &format!("/nar/{{narhash:[{0}]{{52}}}}.nar", NIXBASE32_ALPHABET),
+                // hashes are matched case-insensitively, so the extractors can redirect to the
+                // canonical lowercase url
+                &format!("/nar/{{narhash:(?i:[{0}]{{52}})}}.nar", NIXBASE32_ALPHABET),
                 web::get().to(nar::get),
             )
             .route(
@@ -126,16 +217,36 @@ async fn main() -> std::io::Result<()> {
                 // While we don't do that, if nix-serve is replaced with harmonia, the old nar URLs
                 // will stay in client caches for a while - so support them anyway.
                 &format!(
-                    "/nar/{{outhash:[{0}]{{32}}}}-{{narhash:[{0}]{{52}}}}.nar",
+                    "/nar/{{outhash:(?i:[{0}]{{32}})}}-{{narhash:(?i:[{0}]{{52}})}}.nar",
                     NIXBASE32_ALPHABET
                 ),
                 web::get().to(nar::get),
             )
+            .route("/closure/{hash}.tar.zst", web::get().to(closure::get))
+            .route(
+                "/nar-delta/{from}/{to}.nar.zst",
+                web::get().to(nardelta::get),
+            )
+            .route("/bulk", web::post().to(bulk::post))
             .route("/serve/{hash}{path:.*}", web::get().to(serve::get))
             .route("/log/{drv}", web::get().to(buildlog::get))
             .route("/version", web::get().to(version::get))
             .route("/health", web::get().to(health::get))
+            .route("/healthz/live", web::get().to(health::live))
+            .route("/healthz/ready", web::get().to(health::ready))
             .route("/nix-cache-info", web::get().to(cacheinfo::get))
+            .route("/admin/gc-plan", web::get().to(admin::gc_plan))
+            .route("/admin/gc-roots", web::get().to(admin::list_pins))
+            .route("/admin/gc-roots/{name}", web::put().to(admin::pin))
+            .route("/admin/gc-roots/{name}", web::delete().to(admin::unpin))
+            .service(
+                web::scope("/api/v1")
+                    .route("/narinfo/{hash}", web::get().to(api::narinfo))
+                    .route("/path-info/{hash}", web::get().to(api::path_info))
+                    .route("/paths", web::post().to(api::paths))
+                    .route("/stats", web::get().to(api::stats))
+                    .route("/top-paths", web::get().to(api::top_paths)),
+            )
     })
     // default is 5 seconds, which is too small when doing mass requests on slow machines
     .client_request_timeout(Duration::from_secs(30))
@@ -143,5 +254,10 @@ async fn main() -> std::io::Result<()> {
     .max_connection_rate(c.max_connection_rate)
     .bind(c.bind.clone())?
     .run()
-    .await
+    .await;
+    // counters since the last flush would be lost otherwise
+    if let Err(e) = c.stats.flush() {
+        log::error!("Failed to flush access statistics: {e:?}");
+    }
+    res
 }
@@ -7,15 +7,17 @@ use actix_web::{http, web, HttpRequest, HttpResponse};
 use anyhow::{bail, Context, Result};
 use libnixstore::Radix;
 use serde::Deserialize;
+use sha2::{Digest, Sha256};
 use std::fs::{self, Metadata};
 use std::os::unix::ffi::OsStrExt;
 use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
-use sync::mpsc::Sender;
+use sync::mpsc::{Receiver, Sender};
 use tokio::fs::File;
 use tokio::io::AsyncReadExt;
 
 use crate::config::Config;
+use crate::hash::{self, NarHash52, NixHash32, UrlHash, HASH_PART_LEN};
 use crate::{cache_control_max_age_1y, some_or_404};
 use std::ffi::{OsStr, OsString};
 use tokio::{sync, task};
@@ -26,13 +28,6 @@ pub struct NarRequest {
     hash: Option<String>,
 }
 
-/// Represents the parsed parts in a NAR URL.
-#[derive(Debug, Deserialize)]
-pub struct PathParams {
-    narhash: String,
-    outhash: Option<String>,
-}
-
 // TODO(conni2461): still missing
 // - handle downloadHash/downloadSize and fileHash/fileSize after implementing compression
 
@@ -66,7 +61,7 @@ impl HttpRange {
 
 // We send this error across thread boundaries, so it must be Send + Sync
 #[derive(Debug)]
-enum ThreadSafeError {}
+pub(crate) struct ThreadSafeError;
 impl std::error::Error for ThreadSafeError {}
 impl std::fmt::Display for ThreadSafeError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -74,7 +69,7 @@ impl std::fmt::Display for ThreadSafeError {
     }
 }
 
-fn alignment(size: u64) -> usize {
+pub(crate) fn alignment(size: u64) -> usize {
     let align = 8 - (size % 8);
     if align == 8 {
         0
@@ -165,7 +160,7 @@ async fn dump_contents(
 }
 
 #[cfg(target_os = "macos")]
-fn strip_case_hack_suffix(s: &OsStr) -> &OsStr {
+pub(crate) fn strip_case_hack_suffix(s: &OsStr) -> &OsStr {
     let needle = b"~nix~case~hack~";
     let pos = s
         .as_bytes()
@@ -179,7 +174,7 @@ fn strip_case_hack_suffix(s: &OsStr) -> &OsStr {
 }
 
 #[cfg(not(target_os = "macos"))]
-fn strip_case_hack_suffix(s: &OsStr) -> &OsStr {
+pub(crate) fn strip_case_hack_suffix(s: &OsStr) -> &OsStr {
     s
 }
 
@@ -270,7 +265,10 @@ async fn dump_symlink(frame: &Frame, tx: &Sender<Result<Bytes, ThreadSafeError>>
     Ok(())
 }
 
-async fn dump_path(path: PathBuf, tx: &Sender<Result<Bytes, ThreadSafeError>>) -> Result<()> {
+pub(crate) async fn dump_path(
+    path: PathBuf,
+    tx: &Sender<Result<Bytes, ThreadSafeError>>,
+) -> Result<()> {
     write_byte_slices(tx, &[b"nix-archive-1"]).await?;
     let mut stack = vec![Frame::new(path).await?];
 
@@ -322,14 +320,53 @@ async fn dump_path(path: PathBuf, tx: &Sender<Result<Bytes, ThreadSafeError>>) -
     Ok(())
 }
 
+/// Forwards the dumped nar from `rx` to `tx` and hashes it on the way. A mismatch with the
+/// registered `expected` hash means the store path was modified or corrupted on disk.
+async fn forward_verified(
+    store_path: &str,
+    expected: &str,
+    mut rx: Receiver<Result<Bytes, ThreadSafeError>>,
+    tx: &Sender<Result<Bytes, ThreadSafeError>>,
+) -> Result<()> {
+    let mut hasher = Sha256::new();
+    while let Some(data) = rx.recv().await {
+        let Ok(data) = data else {
+            // the dumper already logged the error
+            let _ = tx.send(Err(ThreadSafeError)).await;
+            return Ok(());
+        };
+        hasher.update(&data);
+        if tx.send(Ok(data)).await.is_err() {
+            // client went away before we saw the whole nar
+            return Ok(());
+        }
+    }
+    let digest = format!("{:x}", hasher.finalize());
+    let actual = format!(
+        "sha256:{}",
+        libnixstore::convert_hash("sha256", &digest, Radix::Base32)?
+    );
+    if actual != expected {
+        log::warn!(
+            "Served nar of {} does not match its registered hash: expected {}, got {}. The store path might be corrupted, consider running `nix-store --verify --check-contents`",
+            store_path,
+            expected,
+            actual
+        );
+    }
+    Ok(())
+}
+
 pub(crate) async fn get(
-    path: web::Path<PathParams>,
+    narhash: NarHash52,
     req: HttpRequest,
     q: web::Query<NarRequest>,
     settings: web::Data<Config>,
 ) -> Result<HttpResponse, Box<dyn Error>> {
-    // Extract the narhash from the query parameter, and bail out if it's missing or invalid.
-    let narhash = some_or_404!(Some(path.narhash.as_str()));
+    let path_outhash = match NixHash32::from_segment(&req, "outhash") {
+        Ok(outhash) => outhash,
+        Err(err) => return Ok(err.error_response()),
+    };
 
     // lookup the store path.
     let store_path = some_or_404!({
@@ -337,12 +374,12 @@ pub(crate) async fn get(
         // However, when processing nix-serve URLs, it's present in the path
         // directly.
         if let Some(outhash) = &q.hash {
-            Some(outhash.as_str())
+            hash::parse(outhash, HASH_PART_LEN).map(UrlHash::into_inner)
         } else {
-            path.outhash.as_deref()
+            path_outhash.map(|outhash| outhash.as_str().to_owned())
         }
     }
-    .and_then(libnixstore::query_path_from_hash_part));
+    .and_then(|outhash| libnixstore::query_path_from_hash_part(&outhash)));
 
     // lookup the path info.
     let info = libnixstore::query_path_info(&store_path, Radix::default())?;
@@ -358,7 +395,18 @@ pub(crate) async fn get(
     let mut res = HttpResponse::Ok();
 
     let (tx, rx) = tokio::sync::mpsc::channel::<Result<Bytes, ThreadSafeError>>(1000);
-    let rx = tokio_stream::wrappers::ReceiverStream::new(rx);
+
+    // partial content is never compressed
+    let encoding = if req.headers().contains_key(http::header::RANGE) {
+        None
+    } else {
+        settings.compressor.negotiate(
+            req.headers()
+                .get(http::header::ACCEPT_ENCODING)
+                .and_then(|v| v.to_str().ok()),
+        )
+    };
+    let compressor = settings.compressor.clone();
 
     // Credit actix_web actix-files: https://github.com/actix/actix-web/blob/master/actix-files/src/named.rs#L525
     if let Some(ranges) = req.headers().get(http::header::RANGE) {
@@ -430,20 +478,53 @@ pub(crate) async fn get(
                 send += len;
             }
         });
+    } else if settings.verify_nar_hash {
+        let (tx2, rx2) = tokio::sync::mpsc::channel::<Result<Bytes, ThreadSafeError>>(1000);
+        let expected = info.narhash;
+        let path = store_path.clone();
+        task::spawn(async move {
+            let err = dump_path(settings.store.get_real_path(&store_path), &tx2).await;
+            if let Err(err) = err {
+                log::error!("Error dumping path {}: {:?}", store_path, err);
+                let _ = tx2.send(Err(ThreadSafeError)).await;
+            }
+        });
+        task::spawn(async move {
+            let err = forward_verified(&path, &expected, rx2, &tx).await;
+            if let Err(err) = err {
+                log::error!("Error verifying nar hash of {}: {:?}", path, err);
+            }
+        });
     } else {
         task::spawn(async move {
             let err = dump_path(settings.store.get_real_path(&store_path), &tx).await;
             if let Err(err) = err {
                 log::error!("Error dumping path {}: {:?}", store_path, err);
+                // abort the response instead of ending it early, the compressed stream can't
+                // tell a truncated nar from a complete one otherwise
+                let _ = tx.send(Err(ThreadSafeError)).await;
             }
         });
     };
 
-    Ok(res
-        .insert_header((http::header::CONTENT_TYPE, "application/x-nix-archive"))
+    res.insert_header((http::header::CONTENT_TYPE, "application/x-nix-archive"))
         .insert_header((http::header::ACCEPT_RANGES, "bytes"))
-        .insert_header(cache_control_max_age_1y())
-        .body(actix_web::body::SizedStream::new(rlength, rx)))
+        .insert_header(cache_control_max_age_1y());
+    if compressor.enabled() {
+        res.insert_header((http::header::VARY, "Accept-Encoding"));
+    }
+    if let Some(encoding) = encoding {
+        return Ok(res
+            .insert_header((http::header::CONTENT_ENCODING, encoding.name()))
+            .body(actix_web::body::BodyStream::new(
+                compressor.compress(encoding, rx),
+            )));
+    }
+
+    Ok(res.body(actix_web::body::SizedStream::new(
+        rlength,
+        tokio_stream::wrappers::ReceiverStream::new(rx),
+    )))
 }
 
 #[cfg(test)]
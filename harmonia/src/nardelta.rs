@@ -0,0 +1,139 @@
+use std::error::Error;
+use std::io::Write;
+
+use actix_web::web::Bytes;
+use actix_web::{http, web, HttpRequest, HttpResponse};
+use anyhow::{bail, Context, Result};
+use libnixstore::Radix;
+use tokio::sync::mpsc;
+use tokio::task;
+
+use crate::config::Config;
+use crate::hash::NixHash32;
+use crate::nar::{dump_path, ThreadSafeError};
+use crate::{admin, cache_control_max_age_1y, cache_control_no_store, nixhash, some_or_404};
+
+/// Smallest window log zstd accepts.
+const WINDOW_LOG_MIN: u32 = 10;
+
+/// Returns the smallest zstd window log covering `size` bytes, so the whole base nar stays
+/// addressable while the target is compressed.
+fn window_log(size: u64) -> u32 {
+    let bits = u64::BITS - size.saturating_sub(1).leading_zeros();
+    bits.clamp(WINDOW_LOG_MIN, 30)
+}
+
+/// Compresses `target` using `base` as a raw content dictionary, like `zstd --patch-from`.
+/// The result can be decompressed with `zstd -d --long=<window log> -D <base nar>`.
+fn encode_delta(base: &[u8], target: &[u8], level: i32) -> Result<Vec<u8>> {
+    let mut encoder = zstd::stream::write::Encoder::with_dictionary(Vec::new(), level, base)
+        .context("Failed to create encoder")?;
+    encoder.long_distance_matching(true)?;
+    encoder.window_log(window_log(base.len() as u64 + target.len() as u64))?;
+    encoder.include_checksum(true)?;
+    encoder.set_pledged_src_size(Some(target.len() as u64))?;
+    encoder.write_all(target)?;
+    Ok(encoder.finish()?)
+}
+
+async fn dump_to_vec(settings: &Config, store_path: &str) -> Result<Vec<u8>> {
+    let (tx, mut rx) = mpsc::channel::<Result<Bytes, ThreadSafeError>>(1000);
+    let real_path = settings.store.get_real_path(store_path);
+    let dumper = task::spawn(async move { dump_path(real_path, &tx).await });
+    let mut nar = Vec::new();
+    while let Some(Ok(data)) = rx.recv().await {
+        nar.extend_from_slice(&data);
+    }
+    dumper
+        .await?
+        .with_context(|| format!("Error dumping path {store_path}"))?;
+    Ok(nar)
+}
+
+pub(crate) async fn get(
+    req: HttpRequest,
+    settings: web::Data<Config>,
+) -> Result<HttpResponse, Box<dyn Error>> {
+    // every request dumps both nars into memory and compresses them, so don't let anyone do that
+    if let Some(res) = admin::check_token(&req, &settings) {
+        return Ok(res);
+    }
+    let Some(max_nar_size) = settings.nar_delta.max_nar_size else {
+        return Ok(HttpResponse::NotFound()
+            .insert_header(cache_control_no_store())
+            .finish());
+    };
+    let (from, to) = match (
+        NixHash32::from_required_segment(&req, "from"),
+        NixHash32::from_required_segment(&req, "to"),
+    ) {
+        (Ok(from), Ok(to)) => (from, to),
+        (Err(err), _) | (_, Err(err)) => return Ok(err.error_response()),
+    };
+    let from = some_or_404!(nixhash(from.as_str()));
+    let to = some_or_404!(nixhash(to.as_str()));
+
+    // both nars are held in memory while compressing
+    for path in [&from, &to] {
+        let info = libnixstore::query_path_info(path, Radix::default())?;
+        if info.size > max_nar_size {
+            return Ok(HttpResponse::PayloadTooLarge()
+                .insert_header(cache_control_no_store())
+                .body(format!("nar of {path} exceeds {max_nar_size} bytes")));
+        }
+    }
+
+    let base = dump_to_vec(&settings, &from).await?;
+    let target = dump_to_vec(&settings, &to).await?;
+    let level = settings.nar_delta.level;
+    let window_log = window_log(base.len() as u64 + target.len() as u64);
+    let delta = web::block(move || {
+        if base.is_empty() || target.is_empty() {
+            bail!("empty nar");
+        }
+        encode_delta(&base, &target, level)
+    })
+    .await??;
+
+    Ok(HttpResponse::Ok()
+        .insert_header((http::header::CONTENT_TYPE, "application/zstd"))
+        .insert_header(("X-Nar-Delta-From", from))
+        .insert_header(("X-Nar-Delta-Window-Log", window_log.to_string()))
+        .insert_header(cache_control_max_age_1y())
+        .body(delta))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_window_log() {
+        assert_eq!(window_log(0), WINDOW_LOG_MIN);
+        assert_eq!(window_log(1 << 20), 20);
+        assert_eq!(window_log((1 << 20) + 1), 21);
+        assert_eq!(window_log(u64::MAX), 30);
+    }
+
+    #[test]
+    fn test_delta_roundtrip() -> Result<()> {
+        let base = (0..200_000u32)
+            .flat_map(|i| (i.wrapping_mul(2654435761)).to_le_bytes())
+            .collect::<Vec<_>>();
+        let mut target = base.clone();
+        target[1000..1010].copy_from_slice(b"0123456789");
+        target.extend_from_slice(b"appended");
+
+        let delta = encode_delta(&base, &target, 3)?;
+        let plain = zstd::encode_all(&target[..], 3)?;
+        assert!(delta.len() * 10 < plain.len());
+
+        let mut decoder = zstd::stream::read::Decoder::with_dictionary(&delta[..], &base)?;
+        decoder.window_log_max(window_log((base.len() + target.len()) as u64))?;
+        let mut decoded = Vec::new();
+        decoder.read_to_end(&mut decoded)?;
+        assert_eq!(decoded, target);
+        Ok(())
+    }
+}
@@ -1,10 +1,11 @@
 use std::{error::Error, path::Path};
 
-use actix_web::{http, web, HttpResponse};
+use actix_web::{http, web, HttpRequest, HttpResponse};
 use libnixstore::Radix;
 use serde::{Deserialize, Serialize};
 
 use crate::config::Config;
+use crate::hash::NixHash32;
 use crate::{cache_control_max_age_1d, nixhash, some_or_404};
 
 #[derive(Debug, Deserialize)]
@@ -13,16 +14,16 @@ pub struct Param {
 }
 
 #[derive(Debug, Serialize)]
-struct NarInfo {
+pub(crate) struct NarInfo {
     store_path: String,
-    url: String,
+    pub(crate) url: String,
     compression: String,
     nar_hash: String,
     nar_size: u64,
     references: Vec<String>,
     deriver: Option<String>,
     system: Option<String>,
-    sigs: Vec<String>,
+    pub(crate) sigs: Vec<String>,
     ca: Option<String>,
 }
 
@@ -70,7 +71,7 @@ fn extract_filename(path: &str) -> Option<String> {
         .and_then(|v| v.to_str().map(ToOwned::to_owned))
 }
 
-fn query_narinfo(
+pub(crate) fn query_narinfo(
     store_path: &str,
     hash: &str,
     sign_keys: &Vec<String>,
@@ -124,7 +125,7 @@ fn query_narinfo(
     Ok(res)
 }
 
-fn format_narinfo_txt(narinfo: &NarInfo) -> String {
+pub(crate) fn format_narinfo_txt(narinfo: &NarInfo) -> String {
     let mut res = vec![
         format!("StorePath: {}", narinfo.store_path),
         format!("URL: {}", narinfo.url),
@@ -160,13 +161,14 @@ fn format_narinfo_txt(narinfo: &NarInfo) -> String {
 }
 
 pub(crate) async fn get(
-    hash: web::Path<String>,
+    hash: NixHash32,
     param: web::Query<Param>,
+    req: HttpRequest,
     settings: web::Data<Config>,
 ) -> Result<HttpResponse, Box<dyn Error>> {
-    let hash = hash.into_inner();
-    let store_path = some_or_404!(nixhash(&hash));
-    let narinfo = query_narinfo(&store_path, &hash, &settings.secret_keys)?;
+    let store_path = some_or_404!(nixhash(hash.as_str()));
+    let host = settings.for_request(&req);
+    let narinfo = query_narinfo(&store_path, hash.as_str(), host.secret_keys)?;
 
     if param.json.is_some() {
         Ok(HttpResponse::Ok()
@@ -1,13 +1,161 @@
+use std::collections::BTreeMap;
 use std::error::Error;
+use std::fs;
+use std::mem::size_of;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
 
 use actix_web::{http, web, HttpResponse};
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Map, Value};
 
+use crate::config::Config;
+use crate::hash::NixHash32;
+use crate::nar::{alignment, strip_case_hack_suffix};
 use crate::{cache_control_max_age_1y, nixhash, some_or_404};
 
-pub(crate) async fn get(hash: web::Path<String>) -> Result<HttpResponse, Box<dyn Error>> {
-    let store_path = some_or_404!(nixhash(&hash));
+/// Tracks the position in the nar that `nar::dump_path` would produce, without producing it.
+struct NarPosition {
+    offset: u64,
+}
+
+impl NarPosition {
+    fn strings(&mut self, strings: &[&[u8]]) {
+        for s in strings {
+            self.offset +=
+                size_of::<u64>() as u64 + s.len() as u64 + alignment(s.len() as u64) as u64;
+        }
+    }
+
+    /// Lists `path` like nix' `listNar`, adding the offset of the contents of regular files.
+    fn list(&mut self, path: &Path) -> Result<Value> {
+        let metadata = fs::symlink_metadata(path)
+            .with_context(|| format!("Failed to get metadata for path: {}", path.display()))?;
+        let file_type = metadata.file_type();
+        if file_type.is_dir() {
+            self.strings(&[b"(", b"type", b"directory"]);
+            let mut children = BTreeMap::new();
+            for entry in fs::read_dir(path)
+                .with_context(|| format!("Failed to read directory: {}", path.display()))?
+            {
+                let file_name = entry.context("Failed to read directory")?.file_name();
+                children.insert(strip_case_hack_suffix(&file_name).to_owned(), file_name);
+            }
+            let mut entries = Map::new();
+            for (nar_name, name) in children {
+                self.strings(&[
+                    b"entry",
+                    b"(",
+                    b"name",
+                    nar_name.as_encoded_bytes(),
+                    b"node",
+                ]);
+                let entry = self.list(&path.join(name))?;
+                self.strings(&[b")"]);
+                entries.insert(nar_name.to_string_lossy().into_owned(), entry);
+            }
+            self.strings(&[b")"]);
+            Ok(json!({ "type": "directory", "entries": entries }))
+        } else if file_type.is_file() {
+            let executable = metadata.permissions().mode() & 0o100 != 0;
+            if executable {
+                self.strings(&[b"(", b"type", b"regular", b"executable", b"", b"contents"]);
+            } else {
+                self.strings(&[b"(", b"type", b"regular", b"contents"]);
+            }
+            let size = metadata.len();
+            self.offset += size_of::<u64>() as u64;
+            let nar_offset = self.offset;
+            self.offset += size + alignment(size) as u64;
+            self.strings(&[b")"]);
+            let mut file = json!({ "type": "regular", "size": size, "narOffset": nar_offset });
+            if executable {
+                file["executable"] = Value::Bool(true);
+            }
+            Ok(file)
+        } else if file_type.is_symlink() {
+            let target = fs::read_link(path)
+                .with_context(|| format!("Failed to read link target: {}", path.display()))?;
+            let target = target.as_os_str();
+            self.strings(&[
+                b"(",
+                b"type",
+                b"symlink",
+                b"target",
+                target.as_encoded_bytes(),
+                b")",
+            ]);
+            Ok(json!({ "type": "symlink", "target": target.to_string_lossy() }))
+        } else {
+            bail!("Unsupported file type: {:?}", file_type);
+        }
+    }
+}
+
+/// Returns the `.ls` listing of the nar of `path`, including the `narOffset` of every regular
+/// file so clients can fetch single files with range requests.
+fn list_nar(path: &Path) -> Result<Value> {
+    let mut position = NarPosition { offset: 0 };
+    position.strings(&[b"nix-archive-1"]);
+    Ok(json!({ "version": 1, "root": position.list(path)? }))
+}
+
+pub(crate) async fn get(
+    hash: NixHash32,
+    settings: web::Data<Config>,
+) -> Result<HttpResponse, Box<dyn Error>> {
+    let store_path = some_or_404!(nixhash(hash.as_str()));
+    let real_path = settings.store.get_real_path(&store_path);
+    let listing = web::block(move || list_nar(&real_path)).await??;
     Ok(HttpResponse::Ok()
         .insert_header(cache_control_max_age_1y())
         .insert_header(http::header::ContentType(mime::APPLICATION_JSON))
-        .body(libnixstore::get_nar_list(&store_path)?))
+        .body(listing.to_string()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::nar::{dump_path, ThreadSafeError};
+    use actix_web::web::Bytes;
+    use std::os::unix::fs::symlink;
+
+    fn file_at<'a>(nar: &'a [u8], entry: &Value) -> &'a [u8] {
+        let offset = entry["narOffset"].as_u64().unwrap() as usize;
+        let size = entry["size"].as_u64().unwrap() as usize;
+        &nar[offset..offset + size]
+    }
+
+    #[tokio::test]
+    async fn test_nar_offsets() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path().join("root");
+        fs::create_dir_all(root.join("sub/empty"))?;
+        fs::write(root.join("a"), b"hello")?;
+        fs::write(root.join("sub/b"), b"12345678")?;
+        fs::write(root.join("exe"), b"#!/bin/sh\n")?;
+        fs::set_permissions(root.join("exe"), fs::Permissions::from_mode(0o755))?;
+        symlink("a", root.join("link"))?;
+
+        let (tx, mut rx) = tokio::sync::mpsc::channel::<Result<Bytes, ThreadSafeError>>(1000);
+        let dumper = tokio::spawn({
+            let root = root.clone();
+            async move { dump_path(root, &tx).await }
+        });
+        let mut nar = Vec::new();
+        while let Some(Ok(data)) = rx.recv().await {
+            nar.extend_from_slice(&data);
+        }
+        dumper.await??;
+
+        let listing = list_nar(&root)?;
+        let entries = &listing["root"]["entries"];
+        assert_eq!(file_at(&nar, &entries["a"]), b"hello");
+        assert_eq!(file_at(&nar, &entries["sub"]["entries"]["b"]), b"12345678");
+        assert_eq!(file_at(&nar, &entries["exe"]), b"#!/bin/sh\n");
+        assert_eq!(entries["exe"]["executable"], true);
+        assert_eq!(entries["link"]["target"], "a");
+        assert_eq!(entries["sub"]["entries"]["empty"]["type"], "directory");
+        Ok(())
+    }
 }
@@ -1,11 +1,32 @@
 use std::error::Error;
+use std::time::Duration;
 
-use actix_web::{http, web, HttpResponse};
+use actix_web::{http, web, HttpRequest, HttpResponse};
+use askama_escape::{escape as escape_html_entity, Html};
 
+use crate::stats::Stats;
 use crate::BOOTSTRAP_SOURCE;
-use crate::{config, CARGO_HOME_PAGE, CARGO_NAME, CARGO_VERSION};
+use crate::{admin, config, CARGO_HOME_PAGE, CARGO_NAME, CARGO_VERSION};
+
+pub(crate) async fn get(
+    req: HttpRequest,
+    config: web::Data<config::Config>,
+) -> Result<HttpResponse, Box<dyn Error>> {
+    let stats = &config.stats;
+    let host = config.for_request(&req);
+    let narinfo_hits = stats.narinfo_hits();
+    let narinfo_misses = stats.narinfo_misses();
+    let hit_rate = match narinfo_hits + narinfo_misses {
+        0 => "n/a".to_owned(),
+        total => format!("{:.1}%", narinfo_hits as f64 * 100.0 / total as f64),
+    };
+    // request paths reveal what clients download, so they aren't shown on a public page
+    let recent = if config.stats_config.public || admin::authorized(&req, &config) {
+        recent_requests(stats)
+    } else {
+        String::new()
+    };
 
-pub(crate) async fn get(config: web::Data<config::Config>) -> Result<HttpResponse, Box<dyn Error>> {
     Ok(HttpResponse::Ok()
         .insert_header(http::header::ContentType(mime::TEXT_HTML_UTF_8))
         .body(format!(
@@ -34,11 +55,26 @@ pub(crate) async fn get(config: web::Data<config::Config>) -> Result<HttpRespons
       <div class="col text-center">
         <h4 class="mb-3">Cache Info</h4>
         <p>Store Dir: {store}</p>
-        <p>Want Mass Query: 1</p>
+        <p>Want Mass Query: {want_mass_query}</p>
         <p>Priority: {priority}</p>
       </div>
+      <div class="col text-center">
+        <h4 class="mb-3">Configuration</h4>
+        <p>Workers: {workers}</p>
+        <p>Signing Keys: {signing_keys}</p>
+        <p>Compression: {compression}</p>
+        <p>Verify Nar Hash: {verify_nar_hash}</p>
+      </div>
+      <div class="col text-center">
+        <h4 class="mb-3">Statistics</h4>
+        <p>Uptime: {uptime}</p>
+        <p>Requests: {requests}</p>
+        <p>Narinfo Hits/Misses: {narinfo_hits}/{narinfo_misses} ({hit_rate})</p>
+        <p>Nar Downloads: {nar_requests}</p>
+        <p>Server Errors: {errors}</p>
+      </div>
     </div>
-    <hr>
+    <hr>{recent}
     <div class="row">
       <div class="col text-center">
         <small class="d-block mb-3 text-muted">
@@ -51,6 +87,68 @@ pub(crate) async fn get(config: web::Data<config::Config>) -> Result<HttpRespons
 </html>
 "#,
             store = libnixstore::get_store_dir(),
-            priority = config.priority,
+            priority = host.priority,
+            want_mass_query = u8::from(host.want_mass_query),
+            workers = config.workers,
+            signing_keys = host.secret_keys.len(),
+            compression = if config.compressor.enabled() {
+                "enabled"
+            } else {
+                "disabled"
+            },
+            verify_nar_hash = config.verify_nar_hash,
+            uptime = format_duration(stats.uptime()),
+            requests = stats.requests(),
+            narinfo_hits = narinfo_hits,
+            narinfo_misses = narinfo_misses,
+            hit_rate = hit_rate,
+            nar_requests = stats.nar_requests(),
+            errors = stats.errors(),
+            recent = recent,
         )))
 }
+
+fn recent_requests(stats: &Stats) -> String {
+    let rows = stats
+        .recent()
+        .iter()
+        .map(|r| {
+            format!(
+                "            <tr><td>{}</td><td>{}</td><td><code>{}</code></td><td>{}</td><td>{} ms</td></tr>",
+                format_duration(r.time.elapsed().unwrap_or_default()),
+                r.method,
+                escape_html_entity(&r.path, Html),
+                r.status.as_u16(),
+                r.duration.as_millis(),
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    format!(
+        r#"
+    <div class="row">
+      <div class="col">
+        <h4 class="mb-3 text-center">Recent Requests</h4>
+        <table class="table table-sm">
+          <thead>
+            <tr><th>Age</th><th>Method</th><th>Path</th><th>Status</th><th>Duration</th></tr>
+          </thead>
+          <tbody>
+{rows}
+          </tbody>
+        </table>
+      </div>
+    </div>
+    <hr>"#
+    )
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs {
+        0..=59 => format!("{secs}s"),
+        60..=3599 => format!("{}m {}s", secs / 60, secs % 60),
+        3600..=86399 => format!("{}h {}m", secs / 3600, secs % 3600 / 60),
+        _ => format!("{}d {}h", secs / 86400, secs % 86400 / 3600),
+    }
+}
@@ -1,15 +1,28 @@
-use std::path::{Path, PathBuf};
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::fs::Metadata;
+use std::io::Read;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
 
 use actix_files::NamedFile;
-use actix_web::Responder;
-use actix_web::{web, HttpRequest, HttpResponse};
+use actix_web::http::header::{
+    ContentDisposition, DispositionParam, DispositionType, HttpDate, IfModifiedSince, LastModified,
+};
+use actix_web::web::Bytes;
+use actix_web::{http, web, HttpRequest, HttpResponse};
+use actix_web::{HttpMessage, Responder};
 use anyhow::Context;
 use askama_escape::{escape as escape_html_entity, Html};
 use percent_encoding::{utf8_percent_encode, CONTROLS};
+use serde::Deserialize;
 use std::fmt::Write;
+use tokio::io::AsyncReadExt;
 
 use crate::{
-    config::Config, nixhash, some_or_404, ServerResult, BOOTSTRAP_SOURCE, CARGO_NAME, CARGO_VERSION,
+    config::Config, hash::NixHash32, headers::store_content, nixhash, some_or_404, ServerResult,
+    BOOTSTRAP_SOURCE, CARGO_NAME, CARGO_VERSION,
 };
 
 /// Returns percent encoded file URL path.
@@ -48,51 +61,187 @@ fn file_size(bytes: u64) -> String {
     }
 }
 
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum SortKey {
+    #[default]
+    Name,
+    Size,
+    Modified,
+}
+
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ListingParams {
+    #[serde(default)]
+    sort: SortKey,
+    #[serde(default)]
+    order: SortOrder,
+}
+
+struct ListingEntry {
+    name: OsString,
+    metadata: Metadata,
+}
+
+impl ListingEntry {
+    fn file_name(&self) -> &OsStr {
+        &self.name
+    }
+
+    fn icon(&self) -> &'static str {
+        let file_type = self.metadata.file_type();
+        if file_type.is_dir() {
+            "&#x1F4C1;"
+        } else if file_type.is_symlink() {
+            "&#x1F517;"
+        } else {
+            "&#x1F4C4;"
+        }
+    }
+
+    fn size(&self) -> Option<u64> {
+        self.metadata.is_file().then_some(self.metadata.len())
+    }
+
+    fn modified(&self) -> Option<SystemTime> {
+        self.metadata.modified().ok()
+    }
+}
+
+fn sort_entries(entries: &mut [ListingEntry], params: &ListingParams) {
+    entries.sort_by(|a, b| {
+        let ordering = match params.sort {
+            SortKey::Name => a.name.cmp(&b.name),
+            SortKey::Size => a.size().cmp(&b.size()),
+            SortKey::Modified => a.modified().cmp(&b.modified()),
+        }
+        .then_with(|| a.name.cmp(&b.name));
+        let ordering = match params.order {
+            SortOrder::Asc => ordering,
+            SortOrder::Desc => ordering.reverse(),
+        };
+        // directories always come first, regardless of the order
+        b.metadata.is_dir().cmp(&a.metadata.is_dir()).then(ordering)
+    });
+}
+
+/// Returns the header of a sortable column, clicking it again reverses the order.
+fn column_header(label: &str, key: SortKey, params: &ListingParams) -> String {
+    let (order, arrow) = match (params.sort == key, params.order) {
+        (true, SortOrder::Asc) => ("desc", " &#x25B2;"),
+        (true, SortOrder::Desc) => ("asc", " &#x25BC;"),
+        (false, _) => ("asc", ""),
+    };
+    let key = match key {
+        SortKey::Name => "name",
+        SortKey::Size => "size",
+        SortKey::Modified => "modified",
+    };
+    format!("<a href=\"?sort={key}&amp;order={order}\">{label}</a>{arrow}")
+}
+
+/// Renders links to the store path and each directory leading to `dir`.
+fn breadcrumbs(hash: &str, store_path: &Path, dir: &Path) -> String {
+    let store_name = store_path
+        .file_name()
+        .map_or_else(|| hash.into(), |n| n.to_string_lossy());
+    let mut url = PathBuf::from("/serve").join(hash);
+    let mut crumbs = vec![(url.clone(), store_name.into_owned())];
+    for component in dir.components() {
+        url.push(component);
+        crumbs.push((
+            url.clone(),
+            component.as_os_str().to_string_lossy().into_owned(),
+        ));
+    }
+
+    let mut html = String::new();
+    let last = crumbs.len() - 1;
+    for (i, (url, name)) in crumbs.iter().enumerate() {
+        let name = escape_html_entity(name, Html);
+        if i == last {
+            let _ = write!(
+                html,
+                "<li class=\"breadcrumb-item active\" aria-current=\"page\">{name}</li>"
+            );
+        } else {
+            let url = url.to_string_lossy();
+            let _ = write!(
+                html,
+                "<li class=\"breadcrumb-item\"><a href=\"{}/\">{name}</a></li>",
+                encode_file_url!(url),
+            );
+        }
+    }
+    html
+}
+
 pub(crate) fn directory_listing(
-    url_prefix: &Path,
+    hash: &str,
+    store_path: &Path,
+    dir: &Path,
     fs_path: &Path,
-    real_store: &str,
+    params: &ListingParams,
 ) -> ServerResult {
-    let path_without_store = fs_path.strip_prefix(real_store).unwrap_or(fs_path);
-    let index_of = format!(
-        "Index of {}",
-        escape_html_entity(&path_without_store.to_string_lossy(), Html)
-    );
-    let mut rows = String::new();
-
+    let url_prefix = PathBuf::from("/serve").join(hash).join(dir);
+    let mut entries = Vec::new();
     for entry in fs_path
         .read_dir()
         .with_context(|| format!("cannot read directory: {}", fs_path.display()))?
     {
-        let entry = entry.unwrap();
-        let p = match entry.path().strip_prefix(fs_path) {
-            Ok(p) => url_prefix.join(p).to_string_lossy().into_owned(),
-            Err(_) => continue,
-        };
-
-        // if file is a directory, add '/' to the end of the name
+        let entry =
+            entry.with_context(|| format!("cannot read directory: {}", fs_path.display()))?;
         if let Ok(metadata) = entry.metadata() {
-            if metadata.is_dir() {
-                let _ = writeln!(
-                    rows,
-                    "<tr><td><a href=\"{}\">{}/</a></td><td>-</td></tr>",
-                    encode_file_url!(p),
-                    encode_file_name!(entry),
-                );
-            } else {
-                let size = file_size(metadata.len());
-                let _ = writeln!(
-                    rows,
-                    "<tr><td><a href=\"{}\">{}</a></td><td>{size}</td></tr>",
-                    encode_file_url!(p),
-                    encode_file_name!(entry),
-                );
-            }
-        } else {
-            continue;
+            entries.push(ListingEntry {
+                name: entry.file_name(),
+                metadata,
+            });
         }
     }
+    sort_entries(&mut entries, params);
 
+    let mut rows = String::new();
+    if dir != Path::new("") {
+        let parent = url_prefix
+            .parent()
+            .unwrap_or(&url_prefix)
+            .to_string_lossy()
+            .into_owned();
+        let _ = writeln!(
+            rows,
+            "<tr><td>&#x2934; <a href=\"{}/\">..</a></td><td></td><td></td></tr>",
+            encode_file_url!(parent),
+        );
+    }
+    for entry in &entries {
+        let p = url_prefix.join(&entry.name).to_string_lossy().into_owned();
+        // if file is a directory, add '/' to the end of the name
+        let suffix = if entry.metadata.is_dir() { "/" } else { "" };
+        let size = entry.size().map_or_else(|| "-".to_owned(), file_size);
+        let modified = entry
+            .modified()
+            .map_or_else(|| "-".to_owned(), |m| HttpDate::from(m).to_string());
+        let _ = writeln!(
+            rows,
+            "<tr><td>{} <a href=\"{}{suffix}\">{}{suffix}</a></td><td>{size}</td><td>{modified}</td></tr>",
+            entry.icon(),
+            encode_file_url!(p),
+            encode_file_name!(entry),
+        );
+    }
+
+    let breadcrumbs = breadcrumbs(hash, store_path, dir);
+    let name_header = column_header("Name", SortKey::Name, params);
+    let size_header = column_header("Size", SortKey::Size, params);
+    let modified_header = column_header("Modified", SortKey::Modified, params);
     let html = format!(
         r#"
 <!DOCTYPE html>
@@ -106,13 +255,18 @@ pub(crate) fn directory_listing(
 </head>
 <body>
     <div class="container mt-4">
-        <h1>{index_of}</h1>
+        <nav aria-label="breadcrumb">
+            <ol class="breadcrumb fs-4">
+                {breadcrumbs}
+            </ol>
+        </nav>
         <hr>
         <table class="table table-striped">
             <thead>
                 <tr>
-                    <th>Name</th>
-                    <th>Size</th>
+                    <th>{name_header}</th>
+                    <th>{size_header}</th>
+                    <th>{modified_header}</th>
                 </tr>
             </thead>
             <tbody>
@@ -127,50 +281,409 @@ pub(crate) fn directory_listing(
         .body(html))
 }
 
+/// Returns the content type for `path`, preferring the configured `[serve.mime_types]` over the
+/// builtin extension table.
+/// Files without a known extension are recognized by their first bytes.
+async fn content_type(path: &Path, mime_types: &BTreeMap<String, String>) -> mime::Mime {
+    let extension = path.extension().and_then(|e| e.to_str());
+    let known = extension
+        .and_then(|e| mime_types.get(e))
+        // validated in config::parse
+        .and_then(|m| m.parse().ok())
+        .or_else(|| mime_guess::from_path(path).first());
+    if let Some(known) = known {
+        return known;
+    }
+    read_head(path)
+        .await
+        .and_then(|head| sniff_content_type(&head))
+        .unwrap_or(mime::APPLICATION_OCTET_STREAM)
+}
+
+/// Reads the first bytes of a file for `sniff_content_type`, off the worker thread.
+async fn read_head(path: &Path) -> Option<Vec<u8>> {
+    let mut file = tokio::fs::File::open(path).await.ok()?;
+    let mut head = vec![0u8; 512];
+    let n = file.read(&mut head).await.ok()?;
+    head.truncate(n);
+    Some(head)
+}
+
+/// Guesses the content type from the magic bytes at the start of a file.
+fn sniff_content_type(head: &[u8]) -> Option<mime::Mime> {
+    const SIGNATURES: &[(&[u8], &str)] = &[
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"GIF87a", "image/gif"),
+        (b"GIF89a", "image/gif"),
+        (b"%PDF-", "application/pdf"),
+        (b"\x1f\x8b", "application/gzip"),
+        (b"\x28\xb5\x2f\xfd", "application/zstd"),
+        (b"\xfd7zXZ\x00", "application/x-xz"),
+        (b"BZh", "application/x-bzip2"),
+        (b"PK\x03\x04", "application/zip"),
+        (b"\x7fELF", "application/x-executable"),
+    ];
+    if let Some((_, mime)) = SIGNATURES.iter().find(|(magic, _)| head.starts_with(magic)) {
+        return mime.parse().ok();
+    }
+    if head.len() >= 12 && &head[0..4] == b"RIFF" && &head[8..12] == b"WEBP" {
+        return "image/webp".parse().ok();
+    }
+
+    // a multi-byte character might be cut off at the end of `head`
+    let text = match std::str::from_utf8(head) {
+        Ok(text) => text,
+        Err(e) if e.error_len().is_none() => std::str::from_utf8(&head[0..e.valid_up_to()]).ok()?,
+        Err(_) => return None,
+    };
+    if text.contains('\0') {
+        return None;
+    }
+    let start = text.trim_start().to_ascii_lowercase();
+    if start.starts_with("<!doctype html") || start.starts_with("<html") {
+        Some(mime::TEXT_HTML_UTF_8)
+    } else if start.starts_with("<svg") || (start.starts_with("<?xml") && start.contains("<svg")) {
+        Some(mime::IMAGE_SVG)
+    } else {
+        Some(mime::TEXT_PLAIN_UTF_8)
+    }
+}
+
+/// Matches `content_type` against patterns like `image/*` or `application/pdf`.
+fn matches_mime_pattern(content_type: &mime::Mime, pattern: &str) -> bool {
+    match pattern.split_once('/') {
+        Some((type_, "*")) => content_type.type_() == type_,
+        _ => content_type.essence_str() == pattern,
+    }
+}
+
+/// Files are rendered in the browser if their type is in `[serve.inline_types]`, and downloaded
+/// otherwise.
+fn content_disposition(
+    path: &Path,
+    content_type: &mime::Mime,
+    inline_types: &[String],
+) -> ContentDisposition {
+    let disposition = if inline_types
+        .iter()
+        .any(|pattern| matches_mime_pattern(content_type, pattern))
+    {
+        DispositionType::Inline
+    } else {
+        DispositionType::Attachment
+    };
+    let parameters = path
+        .file_name()
+        .map(|name| DispositionParam::Filename(name.to_string_lossy().into_owned()))
+        .into_iter()
+        .collect();
+    ContentDisposition {
+        disposition,
+        parameters,
+    }
+}
+
+fn not_modified_since(req: &HttpRequest, modified: SystemTime) -> bool {
+    match req.get_header::<IfModifiedSince>() {
+        // http dates have a resolution of seconds, so compare the truncated values
+        Some(IfModifiedSince(since)) => {
+            SystemTime::from(HttpDate::from(modified)) <= SystemTime::from(since)
+        }
+        None => false,
+    }
+}
+
+/// Serves a small file from a memory mapping instead of streaming it in chunks.
+async fn small_file(
+    req: &HttpRequest,
+    store_path: String,
+    path: &Path,
+    metadata: &Metadata,
+    content_type: mime::Mime,
+    content_disposition: ContentDisposition,
+) -> ServerResult {
+    let modified = metadata.modified().ok();
+    if let Some(modified) = modified {
+        if not_modified_since(req, modified) {
+            return Ok(HttpResponse::NotModified()
+                .insert_header(LastModified(modified.into()))
+                .finish());
+        }
+    }
+
+    let file_path = path.to_owned();
+    let len = metadata.len();
+    let body = web::block(move || -> anyhow::Result<Option<Bytes>> {
+        let body = if len == 0 {
+            Bytes::new()
+        } else {
+            let mut file = std::fs::File::open(&file_path)
+                .with_context(|| format!("cannot open file: {}", file_path.display()))?;
+            let file_metadata = file
+                .metadata()
+                .with_context(|| format!("cannot stat file: {}", file_path.display()))?;
+            // Accessing a mapping of a file that was truncated meanwhile raises SIGBUS, so
+            // files that are still writable or changed since they were stat'ed are read
+            // instead.
+            if file_metadata.permissions().mode() & 0o222 == 0 && file_metadata.len() == len {
+                // SAFETY: nix never modifies files of valid paths in place. `--repair` and
+                // `--optimise` rename a new file over the old one and garbage collection unlinks
+                // it, all of which leave the mapped inode intact. Only a process writing to the
+                // store behind nix's back, e.g. root making the file writable again, could still
+                // truncate it.
+                let map = unsafe { memmap2::Mmap::map(&file) }
+                    .with_context(|| format!("cannot map file: {}", file_path.display()))?;
+                Bytes::from_owner(map)
+            } else {
+                let mut buf = Vec::with_capacity(len as usize);
+                file.read_to_end(&mut buf)
+                    .with_context(|| format!("cannot read file: {}", file_path.display()))?;
+                Bytes::from(buf)
+            }
+        };
+        // The mapping keeps the data alive even if the path is garbage collected meanwhile, so
+        // make sure we don't serve contents of a path that is no longer in the store.
+        Ok(libnixstore::is_valid_path(&store_path).then_some(body))
+    })
+    .await
+    .context("failed to read file")??;
+    let body = some_or_404!(body);
+
+    let mut res = HttpResponse::Ok();
+    res.content_type(content_type)
+        .insert_header(content_disposition);
+    if let Some(modified) = modified {
+        res.insert_header(LastModified(modified.into()));
+    }
+    Ok(res.body(body))
+}
+
+/// Resolves `dir` relative to `store_path`, following symlinks. Returns `None` if the result is
+/// outside of `store_path`, unless `follow_cross_store` is set and it is inside of another store
+/// path for which `is_valid` returns true.
+fn resolve_path(
+    real_store: &Path,
+    store_path: &Path,
+    dir: &Path,
+    follow_cross_store: bool,
+    is_valid: impl Fn(&Path) -> bool,
+) -> std::io::Result<Option<PathBuf>> {
+    // `..` and absolute paths in the request itself are never legitimate
+    if !dir
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+    {
+        return Ok(None);
+    }
+
+    let root = store_path.canonicalize()?;
+    let full_path = root.join(dir).canonicalize()?;
+    if full_path.starts_with(&root) {
+        return Ok(Some(full_path));
+    }
+    if !follow_cross_store {
+        return Ok(None);
+    }
+
+    let real_store = real_store.canonicalize()?;
+    let other_store_path = full_path
+        .strip_prefix(&real_store)
+        .ok()
+        .and_then(|rel| rel.components().next())
+        .map(|name| real_store.join(name));
+    match other_store_path {
+        Some(other_store_path) if is_valid(&other_store_path) => Ok(Some(full_path)),
+        _ => Ok(None),
+    }
+}
+
+/// Represents the path below the store path in a serve URL.
+#[derive(Debug, Deserialize)]
+pub struct ServePath {
+    path: PathBuf,
+}
+
 pub(crate) async fn get(
-    path: web::Path<(String, PathBuf)>,
+    hash: NixHash32,
+    path: web::Path<ServePath>,
+    params: web::Query<ListingParams>,
     req: HttpRequest,
     settings: web::Data<Config>,
 ) -> ServerResult {
-    let (hash, dir) = path.into_inner();
+    let dir = path.into_inner().path;
     let dir = dir.strip_prefix("/").unwrap_or(&dir);
 
-    let store_path = settings.store.get_real_path(&some_or_404!(nixhash(&hash)));
-    let full_path = if dir == Path::new("") {
-        store_path.clone()
-    } else {
-        store_path.join(dir)
+    let virtual_store_path = some_or_404!(nixhash(hash.as_str()));
+    let store_path = settings.store.get_real_path(&virtual_store_path);
+    let resolve = |dir: &Path| -> anyhow::Result<Option<PathBuf>> {
+        let is_valid = |other_store_path: &Path| {
+            other_store_path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| {
+                    libnixstore::is_valid_path(&format!(
+                        "{}/{}",
+                        settings.store.virtual_store(),
+                        name
+                    ))
+                })
+        };
+        match resolve_path(
+            Path::new(settings.store.real_store()),
+            &store_path,
+            dir,
+            settings.serve.follow_cross_store_symlinks,
+            is_valid,
+        ) {
+            Ok(path) => Ok(path),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| {
+                format!(
+                    "cannot resolve nix store path: {}",
+                    store_path.join(dir).display()
+                )
+            }),
+        }
+    };
+    let full_path = match resolve(dir)? {
+        Some(full_path) => full_path,
+        None => {
+            return Ok(HttpResponse::NotFound()
+                .insert_header(crate::cache_control_no_store())
+                .finish())
+        }
     };
-    let full_path = full_path
-        .canonicalize()
-        .with_context(|| format!("cannot resolve nix store path: {}", full_path.display()))?;
-
-    if !full_path.starts_with(settings.store.real_store()) {
-        return Ok(HttpResponse::NotFound().finish());
-    }
 
     if full_path.is_dir() {
-        let index_file = full_path.join("index.html");
-        if let Ok(stat) = index_file.metadata() {
-            if stat.is_file() {
-                return Ok(NamedFile::open_async(&index_file)
-                    .await
-                    .with_context(|| format!("cannot open {}", index_file.display()))?
-                    .respond_to(&req));
+        if let Some(index_file) = resolve(&dir.join("index.html"))? {
+            if index_file.is_file() {
+                return Ok(store_content(
+                    NamedFile::open_async(&index_file)
+                        .await
+                        .with_context(|| format!("cannot open {}", index_file.display()))?
+                        .respond_to(&req),
+                ));
             }
         }
 
-        let url_prefix = PathBuf::from("/serve").join(&hash);
-        let url_prefix = if dir == Path::new("") {
-            url_prefix
-        } else {
-            url_prefix.join(dir)
-        };
-        directory_listing(&url_prefix, &full_path, settings.store.real_store())
+        directory_listing(hash.as_str(), &store_path, dir, &full_path, &params)
     } else {
-        Ok(NamedFile::open_async(&full_path)
+        let content_type = content_type(&full_path, &settings.serve.mime_types).await;
+        let content_disposition =
+            content_disposition(&full_path, &content_type, &settings.serve.inline_types);
+        let metadata = full_path
+            .metadata()
+            .with_context(|| format!("cannot stat file: {}", full_path.display()))?;
+        // range requests are handled by NamedFile
+        if metadata.len() <= settings.serve.mmap_threshold
+            && !req.headers().contains_key(http::header::RANGE)
+        {
+            return small_file(
+                &req,
+                virtual_store_path,
+                &full_path,
+                &metadata,
+                content_type,
+                content_disposition,
+            )
             .await
-            .with_context(|| format!("cannot open file: {}", full_path.display()))?
-            .respond_to(&req))
+            .map(store_content);
+        }
+        Ok(store_content(
+            NamedFile::open_async(&full_path)
+                .await
+                .with_context(|| format!("cannot open file: {}", full_path.display()))?
+                .set_content_type(content_type)
+                .set_content_disposition(content_disposition)
+                .respond_to(&req),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::symlink;
+
+    struct TestStore {
+        _dir: tempfile::TempDir,
+        store: PathBuf,
+        path: PathBuf,
+        other: PathBuf,
+    }
+
+    fn test_store() -> TestStore {
+        let dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let store = dir.path().join("store");
+        let path = store.join("00000000000000000000000000000000-path");
+        let other = store.join("11111111111111111111111111111111-other");
+        fs::create_dir_all(path.join("dir")).unwrap();
+        fs::create_dir_all(&other).unwrap();
+        fs::write(path.join("dir/file"), b"file").unwrap();
+        fs::write(other.join("file"), b"other").unwrap();
+        fs::write(dir.path().join("secret"), b"secret").unwrap();
+        symlink(path.join("dir/file"), path.join("allowed")).unwrap();
+        symlink("dir/file", path.join("relative")).unwrap();
+        symlink(dir.path().join("secret"), path.join("absolute")).unwrap();
+        symlink("../../secret", path.join("escaping")).unwrap();
+        symlink(other.join("file"), path.join("cross")).unwrap();
+        TestStore {
+            _dir: dir,
+            store,
+            path,
+            other,
+        }
+    }
+
+    fn resolve(t: &TestStore, dir: &str, follow_cross_store: bool) -> Option<PathBuf> {
+        resolve_path(&t.store, &t.path, Path::new(dir), follow_cross_store, |p| {
+            p == t.other
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_resolve_inside_store_path() {
+        let t = test_store();
+        let file = t.path.canonicalize().unwrap().join("dir/file");
+        assert_eq!(resolve(&t, "", false), Some(t.path.canonicalize().unwrap()));
+        assert_eq!(resolve(&t, "dir/file", false), Some(file.clone()));
+        assert_eq!(resolve(&t, "./dir/file", false), Some(file.clone()));
+        assert_eq!(resolve(&t, "allowed", false), Some(file.clone()));
+        assert_eq!(resolve(&t, "relative", false), Some(file));
+    }
+
+    #[test]
+    fn test_resolve_rejects_traversal() {
+        let t = test_store();
+        assert_eq!(resolve(&t, "..", true), None);
+        assert_eq!(resolve(&t, "dir/../../secret", true), None);
+        assert_eq!(resolve(&t, "dir/../file", true), None);
+        assert_eq!(resolve(&t, "/etc/passwd", true), None);
+        // percent encoding is decoded by actix before we see the path, anything left is a
+        // literal file name
+        let encoded = resolve_path(&t.store, &t.path, Path::new("%2e%2e/secret"), true, |_| {
+            true
+        });
+        assert_eq!(encoded.unwrap_err().kind(), std::io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_resolve_rejects_escaping_symlinks() {
+        let t = test_store();
+        assert_eq!(resolve(&t, "absolute", true), None);
+        assert_eq!(resolve(&t, "escaping", true), None);
+    }
+
+    #[test]
+    fn test_resolve_cross_store_symlinks() {
+        let t = test_store();
+        let other_file = t.other.canonicalize().unwrap().join("file");
+        assert_eq!(resolve(&t, "cross", true), Some(other_file));
+        assert_eq!(resolve(&t, "cross", false), None);
+        let invalid = resolve_path(&t.store, &t.path, Path::new("cross"), true, |_| false);
+        assert_eq!(invalid.unwrap(), None);
     }
 }
@@ -0,0 +1,388 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use actix_web::http::{Method, StatusCode};
+use anyhow::{Context, Result};
+use rusqlite::Connection;
+use serde::Serialize;
+use tokio::sync::Notify;
+
+/// Number of requests kept for the dashboard on the index page.
+const RECENT_REQUESTS: usize = 20;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub(crate) struct PathStats {
+    pub(crate) hash: String,
+    pub(crate) requests: u64,
+    pub(crate) bytes: u64,
+}
+
+/// Returns the hash part of the store path a request is for, if it is a narinfo or nar request.
+pub(crate) fn store_hash<'a>(path: &'a str, query: &'a str) -> Option<&'a str> {
+    let hash = if let Some(hash) = path
+        .strip_prefix('/')
+        .and_then(|p| p.strip_suffix(".narinfo"))
+    {
+        hash
+    } else if let Some(nar) = path.strip_prefix("/nar/") {
+        // either /nar/<outhash>-<narhash>.nar or /nar/<narhash>.nar?hash=<outhash>
+        match nar.split_once('-') {
+            Some((hash, _)) => hash,
+            None => query.split('&').find_map(|kv| kv.strip_prefix("hash="))?,
+        }
+    } else {
+        return None;
+    };
+    (hash.len() == 32).then_some(hash)
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct RecentRequest {
+    pub(crate) time: SystemTime,
+    pub(crate) method: Method,
+    pub(crate) path: String,
+    pub(crate) status: StatusCode,
+    pub(crate) duration: Duration,
+}
+
+/// Request counters shared by all workers. The per store path counters are added to a database
+/// if one is configured, everything else lives in memory and is reset on restart.
+#[derive(Debug)]
+pub(crate) struct Stats {
+    started: Instant,
+    requests: AtomicU64,
+    narinfo_hits: AtomicU64,
+    narinfo_misses: AtomicU64,
+    nar_requests: AtomicU64,
+    errors: AtomicU64,
+    recent: Mutex<VecDeque<RecentRequest>>,
+    /// Per store path counters, only holding what wasn't flushed to `db` yet if there is one.
+    paths: Mutex<HashMap<String, PathStats>>,
+    /// Counters taken out of a full `paths` table, waiting for the next flush to `db`.
+    unflushed: Mutex<Vec<PathStats>>,
+    flush_requested: Notify,
+    max_paths: usize,
+    db: Option<Mutex<Connection>>,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            requests: AtomicU64::new(0),
+            narinfo_hits: AtomicU64::new(0),
+            narinfo_misses: AtomicU64::new(0),
+            nar_requests: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            recent: Mutex::new(VecDeque::with_capacity(RECENT_REQUESTS)),
+            paths: Mutex::new(HashMap::new()),
+            unflushed: Mutex::new(Vec::new()),
+            flush_requested: Notify::new(),
+            max_paths: 10000,
+            db: None,
+        }
+    }
+}
+
+impl Stats {
+    pub(crate) fn new(max_paths: usize, database: Option<&str>) -> Result<Self> {
+        let db = match database {
+            Some(path) => {
+                let db = Connection::open(path)
+                    .with_context(|| format!("Couldn't open stats database '{path}'"))?;
+                db.execute_batch(
+                    "CREATE TABLE IF NOT EXISTS paths (
+                        hash TEXT PRIMARY KEY NOT NULL,
+                        requests INTEGER NOT NULL,
+                        bytes INTEGER NOT NULL
+                    )",
+                )
+                .with_context(|| format!("Couldn't create tables in '{path}'"))?;
+                Some(Mutex::new(db))
+            }
+            None => None,
+        };
+        Ok(Self {
+            max_paths,
+            db,
+            ..Default::default()
+        })
+    }
+
+    /// Counts a request for the store path with hash part `hash`, that sent `bytes` bytes.
+    pub(crate) fn record_path(&self, hash: &str, bytes: u64) {
+        let mut paths = self.paths.lock().unwrap_or_else(|e| e.into_inner());
+        if !paths.contains_key(hash) && paths.len() >= self.max_paths {
+            let mut entries = paths.drain().map(|(_, v)| v).collect::<Vec<_>>();
+            if self.db.is_some() {
+                // nothing may be dropped before it is in the database, but writing it here would
+                // block the worker serving this request
+                let mut unflushed = self.unflushed.lock().unwrap_or_else(|e| e.into_inner());
+                unflushed.append(&mut entries);
+                self.flush_requested.notify_one();
+            } else {
+                // keep the more popular half, so this doesn't happen on every new path
+                entries.sort_by_key(|e| std::cmp::Reverse(e.requests));
+                entries.truncate(self.max_paths / 2);
+                paths.extend(entries.into_iter().map(|e| (e.hash.clone(), e)));
+            }
+        }
+        let entry = paths.entry(hash.to_owned()).or_insert_with(|| PathStats {
+            hash: hash.to_owned(),
+            ..Default::default()
+        });
+        entry.requests += 1;
+        entry.bytes += bytes;
+    }
+
+    /// Adds the in-memory per path counters to the database, if one is configured.
+    pub(crate) fn flush(&self) -> Result<()> {
+        let Some(db) = &self.db else {
+            return Ok(());
+        };
+        let mut entries =
+            std::mem::take(&mut *self.unflushed.lock().unwrap_or_else(|e| e.into_inner()));
+        {
+            let mut paths = self.paths.lock().unwrap_or_else(|e| e.into_inner());
+            entries.extend(paths.drain().map(|(_, v)| v));
+        }
+        Self::write(db, &entries)
+    }
+
+    /// Waits until the in-memory table ran full, so it should be flushed before the next
+    /// interval.
+    pub(crate) async fn flush_requested(&self) {
+        self.flush_requested.notified().await
+    }
+
+    fn write(db: &Mutex<Connection>, entries: &[PathStats]) -> Result<()> {
+        let mut db = db.lock().unwrap_or_else(|e| e.into_inner());
+        let tx = db.transaction()?;
+        {
+            let mut stmt = tx.prepare_cached(
+                "INSERT INTO paths (hash, requests, bytes) VALUES (?1, ?2, ?3)
+                 ON CONFLICT(hash) DO UPDATE SET
+                   requests = requests + excluded.requests,
+                   bytes = bytes + excluded.bytes",
+            )?;
+            for e in entries {
+                stmt.execute((&e.hash, e.requests as i64, e.bytes as i64))?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// The `limit` most requested store paths.
+    pub(crate) fn top_paths(&self, limit: usize) -> Result<Vec<PathStats>> {
+        if let Some(db) = &self.db {
+            self.flush().context("Couldn't flush stats database")?;
+            let db = db.lock().unwrap_or_else(|e| e.into_inner());
+            let mut stmt = db.prepare_cached(
+                "SELECT hash, requests, bytes FROM paths ORDER BY requests DESC, hash LIMIT ?1",
+            )?;
+            let rows = stmt.query_map([limit as i64], |row| {
+                Ok(PathStats {
+                    hash: row.get(0)?,
+                    requests: row.get::<_, i64>(1)? as u64,
+                    bytes: row.get::<_, i64>(2)? as u64,
+                })
+            })?;
+            return Ok(rows.collect::<Result<_, _>>()?);
+        }
+        let paths = self.paths.lock().unwrap_or_else(|e| e.into_inner());
+        let mut entries = paths.values().cloned().collect::<Vec<_>>();
+        entries.sort_by(|a, b| b.requests.cmp(&a.requests).then(a.hash.cmp(&b.hash)));
+        entries.truncate(limit);
+        Ok(entries)
+    }
+
+    pub(crate) fn record(&self, method: &Method, path: &str, status: StatusCode, started: Instant) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        if path.ends_with(".narinfo") {
+            if status.is_success() {
+                self.narinfo_hits.fetch_add(1, Ordering::Relaxed);
+            } else if status == StatusCode::NOT_FOUND {
+                self.narinfo_misses.fetch_add(1, Ordering::Relaxed);
+            }
+        } else if path.starts_with("/nar/") {
+            self.nar_requests.fetch_add(1, Ordering::Relaxed);
+        }
+        if status.is_server_error() {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let mut recent = self.recent.lock().unwrap_or_else(|e| e.into_inner());
+        if recent.len() == RECENT_REQUESTS {
+            recent.pop_back();
+        }
+        recent.push_front(RecentRequest {
+            time: SystemTime::now(),
+            method: method.clone(),
+            path: path.to_owned(),
+            status,
+            duration: started.elapsed(),
+        });
+    }
+
+    pub(crate) fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    pub(crate) fn requests(&self) -> u64 {
+        self.requests.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn narinfo_hits(&self) -> u64 {
+        self.narinfo_hits.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn narinfo_misses(&self) -> u64 {
+        self.narinfo_misses.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn nar_requests(&self) -> u64 {
+        self.nar_requests.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn errors(&self) -> u64 {
+        self.errors.load(Ordering::Relaxed)
+    }
+
+    /// The most recent requests, newest first.
+    pub(crate) fn recent(&self) -> Vec<RecentRequest> {
+        let recent = self.recent.lock().unwrap_or_else(|e| e.into_inner());
+        recent.iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let stats = Stats::default();
+        let now = Instant::now();
+        stats.record(&Method::GET, "/abc.narinfo", StatusCode::OK, now);
+        stats.record(&Method::HEAD, "/def.narinfo", StatusCode::NOT_FOUND, now);
+        stats.record(&Method::GET, "/nar/abc.nar", StatusCode::OK, now);
+        stats.record(
+            &Method::GET,
+            "/log/abc",
+            StatusCode::INTERNAL_SERVER_ERROR,
+            now,
+        );
+        assert_eq!(stats.requests(), 4);
+        assert_eq!(stats.narinfo_hits(), 1);
+        assert_eq!(stats.narinfo_misses(), 1);
+        assert_eq!(stats.nar_requests(), 1);
+        assert_eq!(stats.errors(), 1);
+        assert_eq!(stats.recent()[0].path, "/log/abc");
+    }
+
+    #[test]
+    fn test_store_hash() {
+        let hash = "00bgd045z0d4icpbc2yyz4gx48ak44la";
+        let narhash = "1w1fff338fvdw53sqgamddn1b2xgds473pv6y13gizdbqjv4i5p3";
+        assert_eq!(store_hash(&format!("/{hash}.narinfo"), ""), Some(hash));
+        assert_eq!(
+            store_hash(&format!("/nar/{hash}-{narhash}.nar"), ""),
+            Some(hash)
+        );
+        assert_eq!(
+            store_hash(&format!("/nar/{narhash}.nar"), &format!("hash={hash}")),
+            Some(hash)
+        );
+        assert_eq!(store_hash(&format!("/nar/{narhash}.nar"), ""), None);
+        assert_eq!(store_hash("/short.narinfo", ""), None);
+        assert_eq!(store_hash(&format!("/serve/{hash}/"), ""), None);
+    }
+
+    #[test]
+    fn test_top_paths_in_memory() {
+        let stats = Stats::new(4, None).unwrap();
+        for (hash, n) in [("a", 3), ("b", 1), ("c", 2)] {
+            for _ in 0..n {
+                stats.record_path(hash, 10);
+            }
+        }
+        let top = stats.top_paths(2).unwrap();
+        assert_eq!(top.len(), 2);
+        assert_eq!(
+            (top[0].hash.as_str(), top[0].requests, top[0].bytes),
+            ("a", 3, 30)
+        );
+        assert_eq!(top[1].hash, "c");
+
+        // the table is bounded, dropping the least requested half when full
+        stats.record_path("d", 0);
+        stats.record_path("e", 0);
+        let top = stats.top_paths(10).unwrap();
+        assert_eq!(
+            top.iter().map(|e| e.hash.as_str()).collect::<Vec<_>>(),
+            ["a", "c", "e"]
+        );
+    }
+
+    #[test]
+    fn test_top_paths_database() {
+        let dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let db = dir.path().join("stats.sqlite");
+        let stats = Stats::new(100, Some(db.to_str().unwrap())).unwrap();
+        stats.record_path("a", 5);
+        stats.flush().unwrap();
+        stats.record_path("a", 5);
+        stats.record_path("b", 1);
+        let top = stats.top_paths(10).unwrap();
+        assert_eq!(top[0].hash, "a");
+        assert_eq!((top[0].requests, top[0].bytes), (2, 10));
+        drop(stats);
+
+        // counters survive restarts
+        let stats = Stats::new(100, Some(db.to_str().unwrap())).unwrap();
+        stats.record_path("b", 1);
+        let top = stats.top_paths(10).unwrap();
+        assert_eq!(top.len(), 2);
+        assert_eq!((top[1].hash.as_str(), top[1].requests), ("b", 2));
+    }
+
+    #[test]
+    fn test_full_table_is_flushed() {
+        let dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let db = dir.path().join("stats.sqlite");
+        let stats = Stats::new(2, Some(db.to_str().unwrap())).unwrap();
+        stats.record_path("a", 1);
+        stats.record_path("a", 1);
+        stats.record_path("b", 1);
+        // the table is full, so a and b are queued for the flush task instead of dropped
+        stats.record_path("c", 1);
+        assert_eq!(stats.paths.lock().unwrap().len(), 1);
+        assert_eq!(stats.unflushed.lock().unwrap().len(), 2);
+        let top = stats.top_paths(10).unwrap();
+        assert_eq!(
+            top.iter()
+                .map(|e| (e.hash.as_str(), e.requests))
+                .collect::<Vec<_>>(),
+            [("a", 2), ("b", 1), ("c", 1)]
+        );
+    }
+
+    #[test]
+    fn test_recent_is_bounded() {
+        let stats = Stats::default();
+        for i in 0..RECENT_REQUESTS + 5 {
+            stats.record(
+                &Method::GET,
+                &format!("/{i}"),
+                StatusCode::OK,
+                Instant::now(),
+            );
+        }
+        let recent = stats.recent();
+        assert_eq!(recent.len(), RECENT_REQUESTS);
+        assert_eq!(recent[0].path, format!("/{}", RECENT_REQUESTS + 4));
+    }
+}
@@ -34,6 +34,9 @@ impl Store {
         }
         PathBuf::from(virtual_path)
     }
+    pub fn virtual_store(&self) -> &str {
+        &self.virtual_store
+    }
     pub fn real_store(&self) -> &str {
         self.real_store
             .as_ref()
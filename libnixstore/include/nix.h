@@ -16,6 +16,9 @@ InternalDrv derivation_from_path(rust::Str drv_path);
 rust::String get_store_dir();
 rust::String get_real_store_dir();
 rust::String get_build_log(rust::Str derivation_path);
+rust::String get_log_dir();
 rust::String get_nar_list(rust::Str store_path);
+rust::Vec<rust::String> query_dead_paths();
+rust::String add_perm_root(rust::Str store_path, rust::Str gc_root);
 
 } // namespace libnixstore
@@ -18,6 +18,7 @@ mod ffi {
         refs: Vec<String>,
         sigs: Vec<String>,
         ca: String,
+        ultimate: bool,
     }
 
     struct InternalTuple {
@@ -50,7 +51,10 @@ mod ffi {
         fn get_store_dir() -> String;
         fn get_real_store_dir() -> String;
         fn get_build_log(derivation_path: &str) -> Result<String>;
+        fn get_log_dir() -> String;
         fn get_nar_list(store_path: &str) -> Result<String>;
+        fn query_dead_paths() -> Result<Vec<String>>;
+        fn add_perm_root(store_path: &str, gc_root: &str) -> Result<String>;
     }
 }
 
@@ -82,6 +86,9 @@ pub struct PathInfo {
     /// `String` value contains the content hash as well as "some other bits of data"; see
     /// `path-info.hh` for details.
     pub ca: Option<String>,
+    /// Whether the path was built locally rather than substituted, so it is trusted without
+    /// signatures.
+    pub ultimate: bool,
 }
 
 pub struct Drv {
@@ -106,6 +113,7 @@ pub struct Drv {
 
 /// Nix's `libstore` offers two options for representing the
 /// hash-part of store paths.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Radix {
     /// Ordinary hexadecimal, using the 16-character alphabet [0-9a-f]
     Base16,
@@ -134,6 +142,13 @@ pub fn is_valid_path(path: &str) -> bool {
     ffi::is_valid_path(path).unwrap_or(false)
 }
 
+#[inline]
+/// Like [`is_valid_path`], but returns errors of the store, e.g. when the daemon can't be reached,
+/// instead of treating them as an invalid path.
+pub fn try_is_valid_path(path: &str) -> Result<bool, cxx::Exception> {
+    ffi::is_valid_path(path)
+}
+
 #[inline]
 /// Return narhash of a valid path. It is permitted to omit the name part of the store path.
 pub fn query_path_hash(path: &str) -> Result<String, cxx::Exception> {
@@ -153,6 +168,7 @@ pub fn query_path_info(path: &str, radix: Radix) -> Result<PathInfo, cxx::Except
         refs: res.refs,
         sigs: res.sigs,
         ca: string_to_opt(res.ca),
+        ultimate: res.ultimate,
     })
 }
 
@@ -167,6 +183,13 @@ pub fn query_path_from_hash_part(hash_part: &str) -> Option<String> {
     }
 }
 
+#[inline]
+/// Like [`query_path_from_hash_part`], but returns errors of the store instead of treating them as
+/// a missing path.
+pub fn try_query_path_from_hash_part(hash_part: &str) -> Result<Option<String>, cxx::Exception> {
+    ffi::query_path_from_hash_part(hash_part).map(string_to_opt)
+}
+
 #[inline]
 /// Parse the hash from a string representation in the format `[<type>:]<base16|base32|base64>` or
 /// `<type>-<base64>` to a string representation of the hash, in `base-16`, `base-32`. The result
@@ -236,8 +259,29 @@ pub fn get_build_log(derivation_path: &str) -> Option<String> {
     }
 }
 
+#[inline]
+#[must_use]
+/// Returns the directory nix writes build logs to.
+pub fn get_log_dir() -> String {
+    ffi::get_log_dir()
+}
+
 #[inline]
 /// Return a JSON representation as String of the contents of a NAR (except file contents).
 pub fn get_nar_list(store_path: &str) -> Result<String, cxx::Exception> {
     ffi::get_nar_list(store_path)
 }
+
+#[inline]
+/// Return the store paths that a garbage collection would delete right now. Nothing is deleted.
+pub fn query_dead_paths() -> Result<Vec<String>, cxx::Exception> {
+    ffi::query_dead_paths()
+}
+
+#[inline]
+/// Create `gc_root` as a symlink to `store_path` and register it as an indirect garbage collector
+/// root, keeping the closure of `store_path` alive until the symlink is removed. An existing
+/// symlink into the store at `gc_root` is replaced atomically. Returns the path of the root.
+pub fn add_perm_root(store_path: &str, gc_root: &str) -> Result<String, cxx::Exception> {
+    ffi::add_perm_root(store_path, gc_root)
+}
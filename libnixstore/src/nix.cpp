@@ -3,6 +3,7 @@
 #include <nix/canon-path.hh>
 #include <nix/config.h>
 #include <nix/derivations.hh>
+#include <nix/gc-store.hh>
 #include <nix/globals.hh>
 #include <nix/shared.hh>
 #include <nix/store-api.hh>
@@ -118,6 +119,7 @@ InternalPathInfo query_path_info(rust::Str path, bool base32) {
       refs,
       sigs,
       info->ca ? nix::renderContentAddress(*info->ca) : "",
+      info->ultimate,
   };
 }
 
@@ -234,6 +236,10 @@ rust::String get_build_log(rust::Str derivation_path) {
   return "";
 }
 
+rust::String get_log_dir() {
+  return nix::settings.nixLogDir;
+}
+
 rust::String get_nar_list(rust::Str store_path) {
   auto path = nix::CanonPath(STRING_VIEW(store_path));
   nlohmann::json j = {
@@ -244,6 +250,34 @@ rust::String get_nar_list(rust::Str store_path) {
   return j.dump();
 }
 
+rust::Vec<rust::String> query_dead_paths() {
+  auto store = get_store();
+  auto &gc_store = nix::require<nix::GcStore>(*store);
+
+  nix::GCOptions options;
+  options.action = nix::GCOptions::gcReturnDead;
+  nix::GCResults results;
+  gc_store.collectGarbage(options, results);
+
+  rust::Vec<rust::String> paths;
+  paths.reserve(results.paths.size());
+  for (const std::string &path : results.paths) {
+    paths.push_back(path);
+  }
+  return paths;
+}
+
+rust::String add_perm_root(rust::Str store_path, rust::Str gc_root) {
+  auto store = get_store();
+  auto *fs_store = dynamic_cast<nix::LocalFSStore *>(&(*store));
+  if (fs_store == nullptr) {
+    throw nix::Error("store '%s' does not support garbage collector roots",
+                     store->getUri());
+  }
+  return fs_store->addPermRoot(store->parseStorePath(STRING_VIEW(store_path)),
+                              nix::absPath(STRING_VIEW(gc_root)));
+}
+
 class StopDump : public std::exception {
 public:
   const char *what() {